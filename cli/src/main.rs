@@ -1,63 +1,395 @@
 use deku::prelude::*;
-use packet::{LuaServices, Message, Service};
+use futures::{SinkExt, StreamExt};
+use packet::{
+    DetachService, ErrorCode, ExitKind, GetLogs, Handshake, HandshakeReply, HandshakeStatus,
+    LogEvent, LogLine, LogLines, LogStream, LuaServices, Message, MessageCodec, PacketId, Response,
+    Service, ServiceList, ServiceState, StateInfo, SubscribeLogs, HANDSHAKE_REPLY_SIZE,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use std::error::Error;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_util::codec::Framed;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let mut stream = TcpStream::connect("127.0.0.1:8080").await?;
-    let (mut reader, writer) = stream.split();
+const SERVER_ADDR: &str = "127.0.0.1:8080";
+
+// Opens a control connection, downgrading to the server's newest version if it
+// doesn't speak ours and we still support it
+async fn connect() -> Result<TcpStream, Box<dyn Error>> {
+    let mut version = PROTOCOL_VERSION;
+    loop {
+        let mut stream = TcpStream::connect(SERVER_ADDR).await?;
+        let handshake = Handshake::new("cli", version)?.to_bytes()?;
+        stream.write_all(&handshake).await?;
 
-    let svc_name = b"subcustodian_server";
-    let cmd = b"npm";
-    let args = b"run,debug";
-    let path = b"/Users/hanar3/Documents/bitbucket/Etana/subcustodian_server/";
+        let mut reply_bytes = [0; HANDSHAKE_REPLY_SIZE];
+        stream.read_exact(&mut reply_bytes).await?;
+        let reply = HandshakeReply::try_from(&reply_bytes[..])?;
+
+        match HandshakeStatus::try_from(reply.status)? {
+            HandshakeStatus::Accepted => return Ok(stream),
+            HandshakeStatus::UnsupportedVersion
+                if reply.max_version < version && reply.max_version >= MIN_PROTOCOL_VERSION =>
+            {
+                version = reply.max_version;
+            }
+            status => {
+                return Err(format!(
+                    "server rejected handshake ({:?}) for v{}, server speaks v{}..=v{}",
+                    status, version, reply.min_version, reply.max_version
+                )
+                .into())
+            }
+        }
+    }
+}
 
-    let attach_svc = Service {
-        cmd_len: cmd.len() as u8,
-        cmd: cmd.to_vec(),
+type Connection = Framed<TcpStream, MessageCodec>;
 
-        cmd_args: args.to_vec(),
-        cmd_args_len: args.len() as u8,
+// Sends a command and waits for its final response, printing progress updates as
+// they come in. Returns the payload of Response::Ok
+async fn send_command(
+    connection: &mut Connection,
+    message: Message,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    connection.send(message).await?;
 
-        name_len: svc_name.len() as u8,
-        svc_name: svc_name.to_vec(),
+    while let Some(frame) = connection.next().await {
+        match Response::try_from(&frame?.data[..])? {
+            Response::Ok { payload, .. } => return Ok(payload),
+            Response::Partial {
+                done,
+                total,
+                message,
+                ..
+            } => {
+                let message = String::from_utf8_lossy(&message);
+                match total {
+                    // Not part of a batch, e.g. "shutting down"
+                    0 => println!("{}", message),
+                    _ => println!("[{}/{}] {}", done, total, message),
+                }
+            }
+            Response::Failure { code, message, .. } => return Err(server_error(code, &message)),
+        }
+    }
+
+    Err("server closed the connection".into())
+}
+
+fn server_error(code: u16, message: &[u8]) -> Box<dyn Error> {
+    let code = ErrorCode::try_from(code)
+        .map(|code| format!("{:?}", code))
+        .unwrap_or_else(|_| code.to_string());
+    format!(
+        "server error ({}): {}",
+        code,
+        String::from_utf8_lossy(message)
+    )
+    .into()
+}
 
-        svc_type: 1,
+// Prints log lines as the server pushes them, until ctrl-c unsubscribes
+async fn follow_logs(
+    connection: &mut Connection,
+    subscribe: SubscribeLogs,
+) -> Result<(), Box<dyn Error>> {
+    connection
+        .send(Message::new(
+            PacketId::SubscribeLogs,
+            subscribe.to_bytes()?,
+        )?)
+        .await?;
 
-        svc_path: path.to_vec(),
-        svc_path_len: path.len() as u8,
-        svc_port: 5002,
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut unsubscribed = false;
+    loop {
+        tokio::select! {
+            _ = &mut ctrl_c, if !unsubscribed => {
+                connection
+                    .send(Message::new(PacketId::UnsubscribeLogs, vec![])?)
+                    .await?;
+                unsubscribed = true;
+            }
+            frame = connection.next() => {
+                let frame = frame.ok_or("server closed the connection")??;
+                if frame.id == PacketId::LogEvent as u8 {
+                    let event = LogEvent::try_from(&frame.data[..])?;
+                    print_log_line(Some(&String::from_utf8_lossy(&event.service)), &event.line);
+                    continue;
+                }
+                match Response::try_from(&frame.data[..])? {
+                    Response::Ok { .. } => return Ok(()),
+                    Response::Partial { message, .. } => {
+                        eprintln!("{}", String::from_utf8_lossy(&message))
+                    }
+                    Response::Failure { code, message, .. } => {
+                        return Err(server_error(code, &message))
+                    }
+                }
+            }
+        }
+    }
+}
+
+const USAGE: &str = "usage: cli [lua <services.lua> | attach | detach <name|id> | list [--json] | status <name|id> | logs <name|id> [--tail N] [--since 10m|<unix ms>] [--stdout|--stderr] | logs -f [name|id ...] [--stdout|--stderr] | shutdown]";
+
+// `--since` takes either a unix timestamp in ms or how far back to go, e.g. 30s, 10m, 2h
+fn parse_since(since: &str) -> Result<u64, Box<dyn Error>> {
+    let (amount, unit_secs) = match since.char_indices().last() {
+        Some((i, 's')) => (&since[..i], 1),
+        Some((i, 'm')) => (&since[..i], 60),
+        Some((i, 'h')) => (&since[..i], 3600),
+        Some((i, 'd')) => (&since[..i], 86400),
+        _ => return Ok(since.parse()?),
     };
+    let back_ms = amount.parse::<u64>()? * unit_secs * 1000;
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    Ok(now_ms.saturating_sub(back_ms).max(1))
+}
 
-    let attach_svc_bytes = Service::to_bytes(&attach_svc).unwrap();
+fn parse_logs_args(name_or_id: &str, options: &[&str]) -> Result<GetLogs, Box<dyn Error>> {
+    let mut tail = 0;
+    let mut since = 0;
+    let mut stream = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--tail" => tail = options.next().ok_or(USAGE)?.parse()?,
+            "--since" => since = parse_since(options.next().ok_or(USAGE)?)?,
+            "--stdout" => stream = Some(LogStream::Stdout),
+            "--stderr" => stream = Some(LogStream::Stderr),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    Ok(GetLogs::new(name_or_id, tail, since, stream)?)
+}
 
-    let lua_file_path = b"/Users/hanar3/Documents/github/hanar3/localmesh/services.lua";
+// `logs -f` follows every service when none are named
+fn parse_follow_args(args: &[&str]) -> Result<SubscribeLogs, Box<dyn Error>> {
+    let mut names_or_ids = vec![];
+    let mut stream = None;
+    for arg in args.iter() {
+        match *arg {
+            "--stdout" => stream = Some(LogStream::Stdout),
+            "--stderr" => stream = Some(LogStream::Stderr),
+            option if option.starts_with("--") => return Err(USAGE.into()),
+            name_or_id => names_or_ids.push(name_or_id),
+        }
+    }
+    Ok(SubscribeLogs::new(&names_or_ids, stream)?)
+}
 
-    let lua_services_file = LuaServices {
-        filepath_len: lua_file_path.len() as u8,
-        filepath: lua_file_path.to_vec(),
+// e.g. "14:03:27.512 err | TypeError: undefined is not a function", times are UTC.
+// Followed lines carry the service name as well: "14:03:27.512 api err | ..."
+fn print_log_line(service: Option<&str>, line: &LogLine) {
+    let ms = line.at_unix_ms;
+    let stream = match LogStream::try_from(line.stream) {
+        Ok(LogStream::Stdout) => "out",
+        Ok(LogStream::Stderr) => "err",
+        Err(_) => "???",
     };
+    let service = service.map(|service| format!("{} ", service));
+    println!(
+        "{:02}:{:02}:{:02}.{:03} {}{} | {}",
+        ms / 3_600_000 % 24,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000,
+        service.unwrap_or_default(),
+        stream,
+        String::from_utf8_lossy(&line.line)
+    );
+}
 
-    let lua_services_bytes = LuaServices::to_bytes(&lua_services_file).unwrap();
+// e.g. "Ready", "Exited(1)", "Exited(signal 9)"
+fn format_state(state: &StateInfo) -> String {
+    let name = ServiceState::try_from(state.state)
+        .map(|state| format!("{:?}", state))
+        .unwrap_or_else(|_| state.state.to_string());
+    match ExitKind::try_from(state.exit_kind) {
+        Ok(ExitKind::Code) => format!("{}({})", name, state.exit_value),
+        Ok(ExitKind::Signal) => format!("{}(signal {})", name, state.exit_value),
+        _ => name,
+    }
+}
 
-    let attach_service_msg = Message {
-        id: 0x3,
-        data_size: lua_services_bytes.len() as u8,
-        data: lua_services_bytes.to_vec(),
+fn print_service_status(list: &ServiceList, name_or_id: &str) -> Result<(), Box<dyn Error>> {
+    let service = list
+        .services
+        .iter()
+        .find(|service| {
+            service.name == name_or_id.as_bytes() || service.id == name_or_id.as_bytes()
+        })
+        .ok_or_else(|| format!("no attached service named or identified by {}", name_or_id))?;
+
+    println!(
+        "{} ({}) is {}",
+        String::from_utf8_lossy(&service.name),
+        String::from_utf8_lossy(&service.id),
+        format_state(&service.state)
+    );
+    for transition in service.transitions.iter() {
+        println!(
+            "  {:>13}  {}",
+            transition.since_unix_ms,
+            format_state(transition)
+        );
+    }
+    Ok(())
+}
+
+fn format_uptime(secs: u64) -> String {
+    format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn print_service_list(list: &ServiceList, json: bool) {
+    let rows: Vec<[String; 9]> = list
+        .services
+        .iter()
+        .map(|service| {
+            let state = format_state(&service.state);
+            [
+                String::from_utf8_lossy(&service.id).to_string(),
+                String::from_utf8_lossy(&service.name).to_string(),
+                service.svc_type.to_string(),
+                service.port.to_string(),
+                service.pid.to_string(),
+                format_uptime(service.uptime_secs),
+                state,
+                String::from_utf8_lossy(&service.cwd).to_string(),
+                String::from_utf8_lossy(&service.command).to_string(),
+            ]
+        })
+        .collect();
+
+    if json {
+        let services: Vec<serde_json::Value> = list
+            .services
+            .iter()
+            .zip(rows.iter())
+            .map(|(service, row)| {
+                serde_json::json!({
+                    "id": row[0],
+                    "name": row[1],
+                    "type": service.svc_type,
+                    "port": service.port,
+                    "pid": if service.pid == 0 { None } else { Some(service.pid) },
+                    "uptime_secs": service.uptime_secs,
+                    "state": row[6],
+                    "cwd": row[7],
+                    "command": row[8],
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::Array(services));
+        return;
+    }
+
+    let header = [
+        "ID", "NAME", "TYPE", "PORT", "PID", "UPTIME", "STATE", "CWD", "COMMAND",
+    ];
+    let mut widths: Vec<usize> = header.iter().map(|column| column.len()).collect();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(header.to_vec());
+    for row in rows.iter() {
+        print_row(row.iter().map(|cell| cell.as_str()).collect());
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    let message = match args[..] {
+        ["attach"] => {
+            let attach_svc = Service::new(
+                "subcustodian_server",
+                1,
+                "/Users/hanar3/Documents/bitbucket/Etana/subcustodian_server/",
+                "npm",
+                "run,debug",
+                5002,
+            )?;
+            Message::new(PacketId::AttachService, attach_svc.to_bytes()?)?
+        }
+        ["detach", name_or_id] => Message::new(
+            PacketId::DetachService,
+            DetachService::new(name_or_id)?.to_bytes()?,
+        )?,
+        [] | ["lua"] => {
+            let lua_services_file =
+                LuaServices::new("/Users/hanar3/Documents/github/hanar3/localmesh/services.lua")?;
+            Message::new(PacketId::LuaServices, lua_services_file.to_bytes()?)?
+        }
+        ["lua", path] => Message::new(PacketId::LuaServices, LuaServices::new(path)?.to_bytes()?)?,
+        ["shutdown"] => Message::new(PacketId::Shutdown, vec![])?,
+        ["status", name_or_id] => {
+            let mut connection = Framed::new(connect().await?, MessageCodec);
+            let payload = send_command(
+                &mut connection,
+                Message::new(PacketId::ListServices, vec![])?,
+            )
+            .await?;
+            return print_service_status(&ServiceList::try_from(&payload[..])?, name_or_id);
+        }
+        ["logs", "-f", ref args @ ..] => {
+            let subscribe = parse_follow_args(args)?;
+            let mut connection = Framed::new(connect().await?, MessageCodec);
+            return follow_logs(&mut connection, subscribe).await;
+        }
+        ["logs", name_or_id, ref options @ ..] => {
+            let get_logs = parse_logs_args(name_or_id, options)?;
+            let mut connection = Framed::new(connect().await?, MessageCodec);
+            let payload = send_command(
+                &mut connection,
+                Message::new(PacketId::GetLogs, get_logs.to_bytes()?)?,
+            )
+            .await?;
+            for line in LogLines::try_from(&payload[..])?.lines.iter() {
+                print_log_line(None, line);
+            }
+            return Ok(());
+        }
+        ["list"] | ["list", "--json"] => {
+            let mut connection = Framed::new(connect().await?, MessageCodec);
+            let payload = send_command(
+                &mut connection,
+                Message::new(PacketId::ListServices, vec![])?,
+            )
+            .await?;
+            print_service_list(&ServiceList::try_from(&payload[..])?, args.len() == 2);
+            return Ok(());
+        }
+        _ => return Err(USAGE.into()),
     };
 
-    let msg_bytes = Message::to_bytes(&attach_service_msg).unwrap();
-    println!("Attach service size {}", std::mem::size_of::<Service>());
-    println!("Sending bytes: {:?}", msg_bytes);
-    // Send a message to the server
-    writer.try_write(&msg_bytes[..]).unwrap();
-
-    // Read the response from the server
-    let mut buffer = [0; 1024];
-    let n = reader.read(&mut buffer).await?;
-    let response = std::str::from_utf8(&buffer[..n]).unwrap();
-    println!("Response from server: {}", response);
+    let mut connection = Framed::new(connect().await?, MessageCodec);
+
+    // Send a message to the server and wait for the response
+    let payload = send_command(&mut connection, message).await?;
+    println!(
+        "Response from server: {}",
+        String::from_utf8_lossy(&payload)
+    );
 
     Ok(())
 }
@@ -0,0 +1,138 @@
+use crate::{Message, PacketHeader, HEADER_SIZE, WIRE_VERSION};
+use bytes::{Buf, BytesMut};
+use deku::prelude::*;
+use std::fmt;
+use tokio_util::codec::{Decoder, Encoder};
+
+// Upper bound for a single frame, anything bigger is treated as a corrupted stream
+// rather than allocated
+pub const MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    UnsupportedWireVersion(u8),
+    FrameTooLarge(u32),
+    Malformed(DekuError),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "{}", e),
+            CodecError::UnsupportedWireVersion(version) => write!(
+                f,
+                "unsupported wire format version {:#04x} (expected {:#04x})",
+                version, WIRE_VERSION
+            ),
+            CodecError::FrameTooLarge(size) => write!(
+                f,
+                "frame of {} bytes exceeds the {} byte limit",
+                size, MAX_FRAME_SIZE
+            ),
+            CodecError::Malformed(e) => write!(f, "malformed frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<std::io::Error> for CodecError {
+    fn from(e: std::io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+impl From<DekuError> for CodecError {
+    fn from(e: DekuError) -> Self {
+        CodecError::Malformed(e)
+    }
+}
+
+// Splits a byte stream into Messages using the length in their header, so partial
+// reads and several frames arriving in one read are both handled
+#[derive(Debug, Default)]
+pub struct MessageCodec;
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+        match src.first() {
+            None => return Ok(None),
+            Some(&WIRE_VERSION) => {}
+            Some(&version) => return Err(CodecError::UnsupportedWireVersion(version)),
+        }
+
+        if src.len() < HEADER_SIZE {
+            src.reserve(HEADER_SIZE - src.len());
+            return Ok(None);
+        }
+
+        let header = PacketHeader::try_from(&src[..HEADER_SIZE])?;
+        if header.data_size > MAX_FRAME_SIZE {
+            return Err(CodecError::FrameTooLarge(header.data_size));
+        }
+
+        let frame_size = HEADER_SIZE + header.data_size as usize;
+        if src.len() < frame_size {
+            src.reserve(frame_size - src.len());
+            return Ok(None);
+        }
+
+        let message = Message::try_from(&src[..frame_size])?;
+        src.advance(frame_size);
+        Ok(Some(message))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+        if item.data_size > MAX_FRAME_SIZE {
+            return Err(CodecError::FrameTooLarge(item.data_size));
+        }
+        dst.extend_from_slice(&item.to_bytes()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PacketId;
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        Message::new(PacketId::LuaServices, data.to_vec())
+            .unwrap()
+            .to_bytes()
+            .unwrap()
+    }
+
+    #[test]
+    fn decodes_partial_and_coalesced_frames() {
+        let mut codec = MessageCodec;
+        let mut stream = frame(b"first");
+        stream.extend(frame(&[7; 2000]));
+
+        // Header only, then the rest of both frames in a single chunk
+        let mut buf = BytesMut::from(&stream[..4]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&stream[4..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().data, b"first");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().data.len(), 2000);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_unversioned_frames() {
+        let mut buf = BytesMut::from(&[0x3, 0x2, 0x0, 0x0][..]);
+        assert!(matches!(
+            MessageCodec.decode(&mut buf),
+            Err(CodecError::UnsupportedWireVersion(0x3))
+        ));
+    }
+}
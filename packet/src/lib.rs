@@ -1,55 +1,713 @@
+#![allow(clippy::manual_div_ceil)] // triggered by deku derive expansions
+
 use deku::prelude::*;
 
+pub mod codec;
+pub use codec::{CodecError, MessageCodec};
+
+// Every frame starts with the wire format version. v1 frames started directly with
+// the packet id (0x1..=0x3), so versions are numbered from 0x10 to keep them apart.
+pub const WIRE_VERSION: u8 = 0x10;
+
+// version (u8) + id (u8) + data_size (u32)
+pub const HEADER_SIZE: usize = 6;
+
+// Sent by clients as the very first bytes of a control connection, before any frame
+pub const HANDSHAKE_MAGIC: [u8; 4] = *b"LMSH";
+
+// Range of protocol (command set) versions this build understands. Bumped whenever
+// the layout of an existing packet changes
+pub const MIN_PROTOCOL_VERSION: u16 = 5;
+pub const PROTOCOL_VERSION: u16 = 5;
+
+// magic ([u8; 4]) + protocol_version (u16) + client_name_len (u16)
+pub const HANDSHAKE_PREFIX_SIZE: usize = 8;
+
+// status (u8) + min_version (u16) + max_version (u16)
+pub const HANDSHAKE_REPLY_SIZE: usize = 5;
+
+// Length prefixes are checked rather than cast, a field too long for its prefix would
+// otherwise wrap around and corrupt the frame
+fn len_u16(field: &str, len: usize) -> Result<u16, DekuError> {
+    u16::try_from(len).map_err(|_| too_long(field, len, u16::MAX as u64))
+}
+
+fn len_u32(field: &str, len: usize) -> Result<u32, DekuError> {
+    u32::try_from(len).map_err(|_| too_long(field, len, u32::MAX as u64))
+}
+
+fn too_long(field: &str, len: usize, max: u64) -> DekuError {
+    DekuError::InvalidParam(format!(
+        "{} is {} bytes long, at most {} fit",
+        field, len, max
+    ))
+}
+
 #[derive(Debug, DekuRead, DekuWrite)]
 pub struct PacketHeader {
+    pub version: u8,
     pub id: u8,
-    pub data_size: u8,
+    pub data_size: u32,
 }
 
 #[derive(Debug, DekuRead, DekuWrite)]
 pub struct Message {
+    pub version: u8,
     pub id: u8,
-    pub data_size: u8,
+    pub data_size: u32,
     #[deku(count = "data_size")]
     pub data: Vec<u8>,
 }
 
+impl Message {
+    pub fn new(id: PacketId, data: Vec<u8>) -> Result<Message, DekuError> {
+        Ok(Message {
+            version: WIRE_VERSION,
+            id: id as u8,
+            data_size: len_u32("message data", data.len())?,
+            data,
+        })
+    }
+}
+
 #[derive(Debug, DekuRead, DekuWrite)]
 pub struct Service {
-    pub name_len: u8,
+    pub name_len: u16,
     #[deku(count = "name_len")]
     pub svc_name: Vec<u8>,
 
     pub svc_type: u8,
 
-    pub svc_path_len: u8,
+    pub svc_path_len: u16,
     #[deku(count = "svc_path_len")]
     pub svc_path: Vec<u8>,
 
-    pub cmd_len: u8,
+    pub cmd_len: u16,
     #[deku(count = "cmd_len")]
     pub cmd: Vec<u8>,
 
-    pub cmd_args_len: u8,
+    pub cmd_args_len: u16,
     #[deku(count = "cmd_args_len")]
     pub cmd_args: Vec<u8>,
 
     pub svc_port: u16,
+
+    pub restart_policy: u8,
+    // Restarts allowed within the window before the server gives up on the service
+    pub max_restarts: u16,
+    pub restart_window_secs: u32,
+    // Backoff before the first restart, doubled for every following one
+    pub restart_delay_ms: u32,
+
+    pub ready_probe: u8,
+    // HTTP path for ProbeKind::Http, regex for ProbeKind::Stdout
+    pub ready_target_len: u16,
+    #[deku(count = "ready_target_len")]
+    pub ready_target: Vec<u8>,
+    // Expected status for ProbeKind::Http
+    pub ready_status: u16,
+    pub ready_timeout_ms: u32,
+
+    // Set on top of the server's environment, or of nothing when clear_env is 1
+    pub env_count: u16,
+    #[deku(count = "env_count")]
+    pub env: Vec<EnvVar>,
+    pub clear_env: u8,
+}
+
+#[derive(Debug, Clone, DekuRead, DekuWrite)]
+pub struct EnvVar {
+    pub key_len: u16,
+    #[deku(count = "key_len")]
+    pub key: Vec<u8>,
+    pub value_len: u32,
+    #[deku(count = "value_len")]
+    pub value: Vec<u8>,
+}
+
+impl EnvVar {
+    pub fn new(key: &str, value: &str) -> Result<EnvVar, DekuError> {
+        Ok(EnvVar {
+            key_len: len_u16("env key", key.len())?,
+            key: key.as_bytes().to_vec(),
+            value_len: len_u32("env value", value.len())?,
+            value: value.as_bytes().to_vec(),
+        })
+    }
+}
+
+impl Service {
+    // cmd_args travel as a single comma separated string, e.g. "run,debug"
+    pub fn new(
+        name: &str,
+        svc_type: u8,
+        path: &str,
+        cmd: &str,
+        cmd_args: &str,
+        port: u16,
+    ) -> Result<Service, DekuError> {
+        Ok(Service {
+            name_len: len_u16("service name", name.len())?,
+            svc_name: name.as_bytes().to_vec(),
+            svc_type,
+            svc_path_len: len_u16("service path", path.len())?,
+            svc_path: path.as_bytes().to_vec(),
+            cmd_len: len_u16("command", cmd.len())?,
+            cmd: cmd.as_bytes().to_vec(),
+            cmd_args_len: len_u16("command args", cmd_args.len())?,
+            cmd_args: cmd_args.as_bytes().to_vec(),
+            svc_port: port,
+            restart_policy: RestartPolicy::Never as u8,
+            max_restarts: 5,
+            restart_window_secs: 60,
+            restart_delay_ms: 1000,
+            ready_probe: ProbeKind::None as u8,
+            ready_target_len: 0,
+            ready_target: vec![],
+            ready_status: 200,
+            ready_timeout_ms: 60_000,
+            env_count: 0,
+            env: vec![],
+            clear_env: 0,
+        })
+    }
+
+    pub fn with_env(mut self, key: &str, value: &str) -> Result<Service, DekuError> {
+        self.env.push(EnvVar::new(key, value)?);
+        self.env_count = len_u16("env", self.env.len())?;
+        Ok(self)
+    }
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct Handshake {
+    pub magic: [u8; 4],
+    pub protocol_version: u16,
+    pub client_name_len: u16,
+    #[deku(count = "client_name_len")]
+    pub client_name: Vec<u8>,
+}
+
+impl Handshake {
+    pub fn new(client_name: &str, protocol_version: u16) -> Result<Handshake, DekuError> {
+        Ok(Handshake {
+            magic: HANDSHAKE_MAGIC,
+            protocol_version,
+            client_name_len: len_u16("client name", client_name.len())?,
+            client_name: client_name.as_bytes().to_vec(),
+        })
+    }
+}
+
+// The server always answers with the version range it supports, so a rejected
+// client can either give up or reconnect with a version inside the range
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct HandshakeReply {
+    pub status: u8,
+    pub min_version: u16,
+    pub max_version: u16,
+}
+
+impl HandshakeReply {
+    pub fn new(status: HandshakeStatus) -> HandshakeReply {
+        HandshakeReply {
+            status: status as u8,
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeStatus {
+    Accepted = 0x0,
+    BadMagic = 0x1,
+    UnsupportedVersion = 0x2,
+}
+
+impl TryFrom<u8> for HandshakeStatus {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(HandshakeStatus::Accepted),
+            0x1 => Ok(HandshakeStatus::BadMagic),
+            0x2 => Ok(HandshakeStatus::UnsupportedVersion),
+            _ => Err("Handshake status can only include known values to the HandshakeStatus enum"),
+        }
+    }
 }
 
 pub enum PacketId {
     AttachService = 0x1,
     DetachService = 0x2,
     LuaServices = 0x3,
+    Response = 0x4,
+    ListServices = 0x5,
+    GetLogs = 0x6,
+    SubscribeLogs = 0x7,
+    UnsubscribeLogs = 0x8,
+    // Pushed by the server while a SubscribeLogs is active
+    LogEvent = 0x9,
+    // Stops every service and then the server itself, no payload. Answered with a
+    // summary of how each service ended
+    Shutdown = 0xA,
+}
+
+// Every command is answered with one or more Response frames: any number of Partial
+// progress updates followed by exactly one Ok or Failure
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(type = "u8")]
+pub enum Response {
+    #[deku(id = "0x0")]
+    Ok {
+        payload_len: u32,
+        #[deku(count = "payload_len")]
+        payload: Vec<u8>,
+    },
+    #[deku(id = "0x1")]
+    Failure {
+        code: u16,
+        message_len: u32,
+        #[deku(count = "message_len")]
+        message: Vec<u8>,
+    },
+    #[deku(id = "0x2")]
+    Partial {
+        done: u32,
+        total: u32,
+        message_len: u32,
+        #[deku(count = "message_len")]
+        message: Vec<u8>,
+    },
+}
+
+impl Response {
+    pub fn ok(payload: Vec<u8>) -> Result<Response, DekuError> {
+        Ok(Response::Ok {
+            payload_len: len_u32("payload", payload.len())?,
+            payload,
+        })
+    }
+
+    pub fn error(code: ErrorCode, message: &str) -> Result<Response, DekuError> {
+        Ok(Response::Failure {
+            code: code as u16,
+            message_len: len_u32("error message", message.len())?,
+            message: message.as_bytes().to_vec(),
+        })
+    }
+
+    pub fn partial(done: u32, total: u32, message: &str) -> Result<Response, DekuError> {
+        Ok(Response::Partial {
+            done,
+            total,
+            message_len: len_u32("progress message", message.len())?,
+            message: message.as_bytes().to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorCode {
+    Generic = 0x1,
+    MalformedPacket = 0x2,
+    UnknownPacket = 0x3,
+    InvalidService = 0x4,
+    SpawnFailed = 0x5,
+    LuaFile = 0x6,
+    Lua = 0x7,
+    Io = 0x8,
+    UnknownService = 0x9,
+    NotReady = 0xA,
+    // Some services of a batch failed, the message lists every outcome
+    BatchFailed = 0xB,
+    PortInUse = 0xC,
+    StopFailed = 0xD,
+    ShuttingDown = 0xE,
+}
+
+impl TryFrom<u16> for ErrorCode {
+    type Error = &'static str;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0x1 => Ok(ErrorCode::Generic),
+            0x2 => Ok(ErrorCode::MalformedPacket),
+            0x3 => Ok(ErrorCode::UnknownPacket),
+            0x4 => Ok(ErrorCode::InvalidService),
+            0x5 => Ok(ErrorCode::SpawnFailed),
+            0x6 => Ok(ErrorCode::LuaFile),
+            0x7 => Ok(ErrorCode::Lua),
+            0x8 => Ok(ErrorCode::Io),
+            0x9 => Ok(ErrorCode::UnknownService),
+            0xA => Ok(ErrorCode::NotReady),
+            0xB => Ok(ErrorCode::BatchFailed),
+            0xC => Ok(ErrorCode::PortInUse),
+            0xD => Ok(ErrorCode::StopFailed),
+            0xE => Ok(ErrorCode::ShuttingDown),
+            _ => Err("Error code can only include known values to the ErrorCode enum"),
+        }
+    }
 }
 
 #[derive(Debug, DekuRead, DekuWrite)]
 pub struct LuaServices {
-    pub filepath_len: u8,
+    pub filepath_len: u16,
     #[deku(count = "filepath_len")]
     pub filepath: Vec<u8>,
 }
 
+impl LuaServices {
+    pub fn new(filepath: &str) -> Result<LuaServices, DekuError> {
+        Ok(LuaServices {
+            filepath_len: len_u16("lua file path", filepath.len())?,
+            filepath: filepath.as_bytes().to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    Never = 0x0,
+    OnFailure = 0x1,
+    Always = 0x2,
+}
+
+impl TryFrom<u8> for RestartPolicy {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(RestartPolicy::Never),
+            0x1 => Ok(RestartPolicy::OnFailure),
+            0x2 => Ok(RestartPolicy::Always),
+            _ => Err("Restart policy can only include known values to the RestartPolicy enum"),
+        }
+    }
+}
+
+// Spelling used in the lua services file
+impl std::str::FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(format!(
+                "unknown restart policy \"{}\", expected never, on-failure or always",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProbeKind {
+    None = 0x0,
+    Tcp = 0x1,
+    Http = 0x2,
+    Stdout = 0x3,
+}
+
+impl TryFrom<u8> for ProbeKind {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(ProbeKind::None),
+            0x1 => Ok(ProbeKind::Tcp),
+            0x2 => Ok(ProbeKind::Http),
+            0x3 => Ok(ProbeKind::Stdout),
+            _ => Err("Probe kind can only include known values to the ProbeKind enum"),
+        }
+    }
+}
+
+// Matched against both the service name and its id
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct DetachService {
+    pub name_or_id_len: u16,
+    #[deku(count = "name_or_id_len")]
+    pub name_or_id: Vec<u8>,
+}
+
+impl DetachService {
+    pub fn new(name_or_id: &str) -> Result<DetachService, DekuError> {
+        Ok(DetachService {
+            name_or_id_len: len_u16("service name or id", name_or_id.len())?,
+            name_or_id: name_or_id.as_bytes().to_vec(),
+        })
+    }
+}
+
+// Payload of the Response::Ok answering a ListServices request (which carries no data)
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct ServiceList {
+    pub count: u32,
+    #[deku(count = "count")]
+    pub services: Vec<ServiceInfo>,
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct ServiceInfo {
+    pub id_len: u16,
+    #[deku(count = "id_len")]
+    pub id: Vec<u8>,
+
+    pub name_len: u16,
+    #[deku(count = "name_len")]
+    pub name: Vec<u8>,
+
+    pub svc_type: u8,
+    pub port: u16,
+
+    pub cwd_len: u16,
+    #[deku(count = "cwd_len")]
+    pub cwd: Vec<u8>,
+
+    // Command and its arguments joined by spaces
+    pub command_len: u16,
+    #[deku(count = "command_len")]
+    pub command: Vec<u8>,
+
+    // 0 when there is no process
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub state: StateInfo,
+
+    // Most recent state changes, oldest first
+    pub transitions_count: u16,
+    #[deku(count = "transitions_count")]
+    pub transitions: Vec<StateInfo>,
+}
+
+impl ServiceInfo {
+    // Without a process and any earlier transitions, see with_transitions
+    pub fn new(
+        id: &str,
+        name: &str,
+        svc_type: u8,
+        port: u16,
+        cwd: &str,
+        command: &str,
+        state: StateInfo,
+    ) -> Result<ServiceInfo, DekuError> {
+        Ok(ServiceInfo {
+            id_len: len_u16("service id", id.len())?,
+            id: id.as_bytes().to_vec(),
+            name_len: len_u16("service name", name.len())?,
+            name: name.as_bytes().to_vec(),
+            svc_type,
+            port,
+            cwd_len: len_u16("working directory", cwd.len())?,
+            cwd: cwd.as_bytes().to_vec(),
+            command_len: len_u16("command", command.len())?,
+            command: command.as_bytes().to_vec(),
+            pid: 0,
+            uptime_secs: 0,
+            state,
+            transitions_count: 0,
+            transitions: vec![],
+        })
+    }
+
+    pub fn with_transitions(
+        mut self,
+        transitions: Vec<StateInfo>,
+    ) -> Result<ServiceInfo, DekuError> {
+        self.transitions_count = len_u16("transitions", transitions.len())?;
+        self.transitions = transitions;
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, DekuRead, DekuWrite)]
+pub struct StateInfo {
+    pub state: u8,
+    // Only set for ServiceState::Exited
+    pub exit_kind: u8,
+    pub exit_value: i32,
+    // When the service entered this state
+    pub since_unix_ms: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ServiceState {
+    Pending = 0x0,
+    Starting = 0x1,
+    Ready = 0x2,
+    Unhealthy = 0x3,
+    Exited = 0x4,
+    Stopped = 0x5,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExitKind {
+    None = 0x0,
+    Code = 0x1,
+    Signal = 0x2,
+}
+
+impl TryFrom<u8> for ServiceState {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(ServiceState::Pending),
+            0x1 => Ok(ServiceState::Starting),
+            0x2 => Ok(ServiceState::Ready),
+            0x3 => Ok(ServiceState::Unhealthy),
+            0x4 => Ok(ServiceState::Exited),
+            0x5 => Ok(ServiceState::Stopped),
+            _ => Err("Service state can only include known values to the ServiceState enum"),
+        }
+    }
+}
+
+impl TryFrom<u8> for ExitKind {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(ExitKind::None),
+            0x1 => Ok(ExitKind::Code),
+            0x2 => Ok(ExitKind::Signal),
+            _ => Err("Exit kind can only include known values to the ExitKind enum"),
+        }
+    }
+}
+
+// Fetches the buffered output of one service
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct GetLogs {
+    pub name_or_id_len: u16,
+    #[deku(count = "name_or_id_len")]
+    pub name_or_id: Vec<u8>,
+    // Only the last `tail` matching lines, 0 for all of them
+    pub tail: u32,
+    // Only lines logged at or after this time, 0 for no limit
+    pub since_unix_ms: u64,
+    // LogStream, 0 for both
+    pub stream: u8,
+}
+
+impl GetLogs {
+    pub fn new(
+        name_or_id: &str,
+        tail: u32,
+        since_unix_ms: u64,
+        stream: Option<LogStream>,
+    ) -> Result<GetLogs, DekuError> {
+        Ok(GetLogs {
+            name_or_id_len: len_u16("service name or id", name_or_id.len())?,
+            name_or_id: name_or_id.as_bytes().to_vec(),
+            tail,
+            since_unix_ms,
+            stream: stream.map(|stream| stream as u8).unwrap_or(0),
+        })
+    }
+}
+
+// Payload of the Response::Ok answering GetLogs, oldest line first
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct LogLines {
+    pub count: u32,
+    #[deku(count = "count")]
+    pub lines: Vec<LogLine>,
+}
+
+#[derive(Debug, Clone, DekuRead, DekuWrite)]
+pub struct LogLine {
+    pub at_unix_ms: u64,
+    pub stream: u8,
+    // Without the trailing newline
+    pub line_len: u32,
+    #[deku(count = "line_len")]
+    pub line: Vec<u8>,
+}
+
+// Starts following the output of the given services, or of every service (including
+// the ones attached later) when the list is empty. The server answers with a Partial,
+// then pushes LogEvent frames until UnsubscribeLogs (answered with the final Ok) or the
+// connection closes. Any other command ends the subscription with a Failure
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct SubscribeLogs {
+    pub count: u16,
+    #[deku(count = "count")]
+    pub services: Vec<ServiceRef>,
+    // LogStream, 0 for both
+    pub stream: u8,
+}
+
+impl SubscribeLogs {
+    pub fn new(
+        names_or_ids: &[&str],
+        stream: Option<LogStream>,
+    ) -> Result<SubscribeLogs, DekuError> {
+        let services = names_or_ids
+            .iter()
+            .map(|name_or_id| {
+                Ok(ServiceRef {
+                    name_or_id_len: len_u16("service name or id", name_or_id.len())?,
+                    name_or_id: name_or_id.as_bytes().to_vec(),
+                })
+            })
+            .collect::<Result<Vec<ServiceRef>, DekuError>>()?;
+        Ok(SubscribeLogs {
+            count: len_u16("services", services.len())?,
+            services,
+            stream: stream.map(|stream| stream as u8).unwrap_or(0),
+        })
+    }
+}
+
+// Matched against both the service name and its id
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct ServiceRef {
+    pub name_or_id_len: u16,
+    #[deku(count = "name_or_id_len")]
+    pub name_or_id: Vec<u8>,
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+pub struct LogEvent {
+    pub service_len: u16,
+    #[deku(count = "service_len")]
+    pub service: Vec<u8>,
+    pub line: LogLine,
+}
+
+impl LogEvent {
+    pub fn new(service: &str, line: LogLine) -> Result<LogEvent, DekuError> {
+        Ok(LogEvent {
+            service_len: len_u16("service name", service.len())?,
+            service: service.as_bytes().to_vec(),
+            line,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogStream {
+    Stdout = 0x1,
+    Stderr = 0x2,
+}
+
+impl TryFrom<u8> for LogStream {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x1 => Ok(LogStream::Stdout),
+            0x2 => Ok(LogStream::Stderr),
+            _ => Err("Log stream can only include known values to the LogStream enum"),
+        }
+    }
+}
+
 impl TryFrom<u8> for PacketId {
     type Error = &'static str;
 
@@ -58,7 +716,111 @@ impl TryFrom<u8> for PacketId {
             0x1 => Ok(PacketId::AttachService),
             0x2 => Ok(PacketId::DetachService),
             0x3 => Ok(PacketId::LuaServices),
+            0x4 => Ok(PacketId::Response),
+            0x5 => Ok(PacketId::ListServices),
+            0x6 => Ok(PacketId::GetLogs),
+            0x7 => Ok(PacketId::SubscribeLogs),
+            0x8 => Ok(PacketId::UnsubscribeLogs),
+            0x9 => Ok(PacketId::LogEvent),
+            0xA => Ok(PacketId::Shutdown),
             _ => Err("Command can only include known values to the Command enum"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_longer_than_u8_round_trips() {
+        let path = "/a".repeat(200);
+        let service = Service::new("api", 1, &path, "npm", "run,debug", 5002).unwrap();
+        let msg = Message::new(PacketId::AttachService, service.to_bytes().unwrap()).unwrap();
+        let bytes = msg.to_bytes().unwrap();
+
+        let header = PacketHeader::try_from(&bytes[..HEADER_SIZE]).unwrap();
+        assert_eq!(header.version, WIRE_VERSION);
+        assert_eq!(header.data_size as usize, bytes.len() - HEADER_SIZE);
+
+        let decoded = Service::try_from(&Message::try_from(&bytes[..]).unwrap().data[..]).unwrap();
+        assert_eq!(decoded.svc_path, path.as_bytes());
+        assert_eq!(decoded.svc_port, 5002);
+    }
+
+    #[test]
+    fn service_env_round_trips() {
+        let certificate = "x".repeat(300);
+        let mut service = Service::new("api", 1, "/srv/api", "npm", "run,debug", 5002)
+            .and_then(|service| service.with_env("NODE_ENV", "production"))
+            .and_then(|service| service.with_env("EMPTY", ""))
+            .and_then(|service| service.with_env("TLS_CERT", &certificate))
+            .unwrap();
+        service.clear_env = 1;
+
+        let decoded = Service::try_from(&service.to_bytes().unwrap()[..]).unwrap();
+        let env: Vec<(&[u8], &[u8])> = decoded
+            .env
+            .iter()
+            .map(|var| (&var.key[..], &var.value[..]))
+            .collect();
+        assert_eq!(
+            env,
+            [
+                (&b"NODE_ENV"[..], &b"production"[..]),
+                (b"EMPTY", b""),
+                (b"TLS_CERT", certificate.as_bytes()),
+            ]
+        );
+        assert_eq!(decoded.clear_env, 1);
+    }
+
+    #[test]
+    fn fields_too_long_for_their_prefix_are_refused() {
+        let name = "a".repeat(u16::MAX as usize + 1);
+        assert!(Service::new(&name, 1, "/", "npm", "", 5002).is_err());
+        assert!(DetachService::new(&name).is_err());
+        assert!(Service::new("api", 1, "/", "npm", "", 5002)
+            .unwrap()
+            .with_env(&name, "1")
+            .is_err());
+    }
+
+    #[test]
+    fn handshake_prefix_carries_name_length() {
+        let bytes = Handshake::new("cli", PROTOCOL_VERSION)
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        assert_eq!(&bytes[..4], &HANDSHAKE_MAGIC);
+        assert_eq!(bytes.len(), HANDSHAKE_PREFIX_SIZE + 3);
+
+        let reply = HandshakeReply::new(HandshakeStatus::Accepted);
+        assert_eq!(reply.to_bytes().unwrap().len(), HANDSHAKE_REPLY_SIZE);
+    }
+
+    #[test]
+    fn response_variants_round_trip() {
+        let bytes = Response::error(ErrorCode::SpawnFailed, "no such file")
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        match Response::try_from(&bytes[..]).unwrap() {
+            Response::Failure { code, message, .. } => {
+                assert_eq!(ErrorCode::try_from(code), Ok(ErrorCode::SpawnFailed));
+                assert_eq!(message, b"no such file");
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        let bytes = Response::partial(1, 3, "api").unwrap().to_bytes().unwrap();
+        assert!(matches!(
+            Response::try_from(&bytes[..]).unwrap(),
+            Response::Partial {
+                done: 1,
+                total: 3,
+                ..
+            }
+        ));
+    }
+}
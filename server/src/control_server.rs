@@ -0,0 +1,269 @@
+use crate::message_parser;
+use crate::prelude::*;
+use crate::service_logs;
+use deku::prelude::*;
+use futures::{SinkExt, StreamExt};
+use log::{error, info};
+use packet::{
+    ErrorCode, Handshake, HandshakeReply, HandshakeStatus, LogEvent, Message, MessageCodec,
+    PacketId, Response, HANDSHAKE_MAGIC, HANDSHAKE_PREFIX_SIZE, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast::error::RecvError, mpsc, oneshot},
+    task, time,
+};
+use tokio_util::codec::Framed;
+
+type Connection = Framed<TcpStream, MessageCodec>;
+
+// How long a new connection gets to send its handshake before it is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sent to main by a client's Shutdown packet. main answers with the summary once the
+// services are stopped, and waits for `sent` before exiting so the client gets to read it
+pub struct ShutdownRequest {
+    pub summary: oneshot::Sender<String>,
+    pub sent: oneshot::Receiver<()>,
+}
+
+// Accepts control connections until main drops this future, each one is served on its
+// own task so a slow command never holds up other clients
+pub async fn run(addr: &str, shutdown: mpsc::UnboundedSender<ShutdownRequest>) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Control server listening on {}", addr);
+
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        info!("Connection established with {}", peer);
+
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let handshake = time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream))
+                .await
+                .unwrap_or(Err(Error::HandshakeTimeout(HANDSHAKE_TIMEOUT)));
+            if let Err(e) = handshake {
+                error!("Dropping connection from {}: {}", peer, e);
+                return;
+            }
+
+            match serve_connection(Framed::new(stream, MessageCodec), shutdown).await {
+                Ok(()) => info!("Connection with {} closed", peer),
+                Err(e) => error!("Dropping connection from {}: {}", peer, e),
+            }
+        });
+    }
+}
+
+// Nothing but a valid handshake is accepted as the first bytes of a connection, this
+// keeps stray clients (browsers, old cli builds) away from the packet parser
+async fn handshake(stream: &mut TcpStream) -> Result<()> {
+    let mut prefix = [0; HANDSHAKE_PREFIX_SIZE];
+    stream.read_exact(&mut prefix).await?;
+
+    let magic: [u8; 4] = prefix[0..4].try_into().unwrap();
+    if magic != HANDSHAKE_MAGIC {
+        let reply = HandshakeReply::new(HandshakeStatus::BadMagic);
+        stream.write_all(&reply.to_bytes()?).await?;
+        return Err(Error::BadHandshakeMagic(magic));
+    }
+
+    let client_name_len = u16::from_le_bytes([prefix[6], prefix[7]]) as usize;
+    let mut handshake_bytes = prefix.to_vec();
+    handshake_bytes.resize(HANDSHAKE_PREFIX_SIZE + client_name_len, 0);
+    stream
+        .read_exact(&mut handshake_bytes[HANDSHAKE_PREFIX_SIZE..])
+        .await?;
+
+    let handshake = Handshake::try_from(&handshake_bytes[..])?;
+    let client_name = String::from_utf8_lossy(&handshake.client_name);
+
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&handshake.protocol_version) {
+        let reply = HandshakeReply::new(HandshakeStatus::UnsupportedVersion);
+        stream.write_all(&reply.to_bytes()?).await?;
+        return Err(Error::UnsupportedProtocolVersion(
+            handshake.protocol_version,
+        ));
+    }
+
+    let reply = HandshakeReply::new(HandshakeStatus::Accepted);
+    stream.write_all(&reply.to_bytes()?).await?;
+    info!(
+        "Handshake accepted for {} (protocol v{})",
+        client_name, handshake.protocol_version
+    );
+    Ok(())
+}
+
+// Processes framed commands one after the other until the client hangs up
+async fn serve_connection(
+    mut connection: Connection,
+    shutdown: mpsc::UnboundedSender<ShutdownRequest>,
+) -> Result<()> {
+    while let Some(frame) = connection.next().await {
+        let message = match frame {
+            Ok(message) => message,
+            Err(e) => {
+                // The framing can't be trusted anymore, report and hang up
+                let e = Error::from(e);
+                send_response(&mut connection, Response::error(e.code(), &e.to_string())?).await?;
+                return Err(e);
+            }
+        };
+
+        if message.id == PacketId::Shutdown as u8 {
+            return request_shutdown(&mut connection, &shutdown).await;
+        }
+
+        if message.id == PacketId::SubscribeLogs as u8 {
+            if !follow_logs(&mut connection, message).await? {
+                break;
+            }
+            continue;
+        }
+
+        // Commands touch processes, files and locks, keep them off the async workers
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+        let mut command =
+            task::spawn_blocking(move || message_parser::parse_message(message, &progress_tx));
+
+        let result = loop {
+            tokio::select! {
+                Some(update) = progress_rx.recv() => send_response(&mut connection, update).await?,
+                result = &mut command => break result,
+            }
+        };
+        while let Ok(update) = progress_rx.try_recv() {
+            send_response(&mut connection, update).await?;
+        }
+
+        let response = match result {
+            // Too large to send, the client still gets an answer
+            Ok(Ok(payload)) => Response::ok(payload).or_else(|e| {
+                let e = Error::from(e);
+                error!("Command failed: {}", e);
+                Response::error(e.code(), &e.to_string())
+            }),
+            Ok(Err(e)) => {
+                error!("Command failed: {}", e);
+                Response::error(e.code(), &e.to_string())
+            }
+            Err(e) => {
+                error!("Command panicked: {}", e);
+                Response::error(ErrorCode::Generic, &f!("command panicked: {}", e))
+            }
+        };
+        send_response(&mut connection, response?).await?;
+    }
+
+    Ok(())
+}
+
+// Pushes the lines the client asked for as LogEvent frames until it unsubscribes, sends
+// another command (which ends the subscription unprocessed) or hangs up. Returns false
+// in the latter case
+async fn follow_logs(connection: &mut Connection, message: Message) -> Result<bool> {
+    let filter = match task::spawn_blocking(move || message_parser::log_filter(message)).await {
+        Ok(Ok(filter)) => filter,
+        Ok(Err(e)) => {
+            send_response(connection, Response::error(e.code(), &e.to_string())?).await?;
+            return Ok(true);
+        }
+        Err(e) => {
+            let e = f!("command panicked: {}", e);
+            send_response(connection, Response::error(ErrorCode::Generic, &e)?).await?;
+            return Ok(true);
+        }
+    };
+
+    let mut events = service_logs::follow();
+    let following = match &filter.services {
+        Some(services) => services.iter().cloned().collect::<Vec<String>>().join(", "),
+        None => "every service".to_string(),
+    };
+    send_response(
+        connection,
+        Response::partial(0, 0, &f!("following {}", following))?,
+    )
+    .await?;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok((service, entry)) => {
+                    if !filter.matches(&service, &entry) {
+                        continue;
+                    }
+                    let event = LogEvent::new(&service, entry.to_packet())?;
+                    connection
+                        .send(Message::new(PacketId::LogEvent, event.to_bytes()?)?)
+                        .await?;
+                }
+                // The client reads slower than the services print
+                Err(RecvError::Lagged(missed)) => {
+                    let notice = f!("missed {} lines, the client is not keeping up", missed);
+                    send_response(connection, Response::partial(0, 0, &notice)?).await?;
+                }
+                Err(RecvError::Closed) => return Ok(true),
+            },
+            frame = connection.next() => match frame {
+                None => return Ok(false),
+                Some(Err(e)) => {
+                    let e = Error::from(e);
+                    send_response(connection, Response::error(e.code(), &e.to_string())?).await?;
+                    return Err(e);
+                }
+                Some(Ok(message)) if message.id == PacketId::UnsubscribeLogs as u8 => {
+                    send_response(connection, Response::ok(vec![])?).await?;
+                    return Ok(true);
+                }
+                Some(Ok(message)) => {
+                    let e = f!(
+                        "packet {:#04x} ended the log subscription, unsubscribe first",
+                        message.id
+                    );
+                    send_response(connection, Response::error(ErrorCode::Generic, &e)?).await?;
+                    return Ok(true);
+                }
+            },
+        }
+    }
+}
+
+// Hands the shutdown to main and relays the summary, the connection ends with it
+async fn request_shutdown(
+    connection: &mut Connection,
+    shutdown: &mpsc::UnboundedSender<ShutdownRequest>,
+) -> Result<()> {
+    let (summary_tx, summary_rx) = oneshot::channel();
+    let (sent_tx, sent_rx) = oneshot::channel();
+    let request = ShutdownRequest {
+        summary: summary_tx,
+        sent: sent_rx,
+    };
+    if shutdown.send(request).is_ok() {
+        send_response(connection, Response::partial(0, 0, "shutting down")?).await?;
+    }
+
+    let response = match summary_rx.await {
+        Ok(summary) => Response::ok(summary.into_bytes())?,
+        // main stopped listening, a signal or another client got there first
+        Err(_) => {
+            let e = Error::ShuttingDown;
+            Response::error(e.code(), &e.to_string())?
+        }
+    };
+    send_response(connection, response).await?;
+    let _ = sent_tx.send(());
+    Ok(())
+}
+
+async fn send_response(connection: &mut Connection, response: Response) -> Result<()> {
+    connection
+        .send(Message::new(PacketId::Response, response.to_bytes()?)?)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,210 @@
+use crate::prelude::*;
+use crate::service_attacher::{already_attached, Attachable};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Visiting,
+    Done,
+}
+
+// Orders a batch so every service comes after the ones it depends on, services without
+// constraints between them keep the order they were given in
+//
+// `attached` holds the services that are already running, depending on those is fine
+pub fn startup_order(
+    attachables: Vec<Attachable>,
+    attached: &HashSet<String>,
+) -> Result<Vec<Attachable>> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (i, attachable) in attachables.iter().enumerate() {
+        if index.insert(&attachable.name, i).is_some() {
+            return Err(Error::InvalidService(f!(
+                "{} is defined more than once",
+                attachable.name
+            )));
+        }
+        // Attaching it again would leave the running one unreachable
+        if attached.contains(&attachable.name) {
+            return Err(already_attached(&attachable.name));
+        }
+    }
+
+    for attachable in attachables.iter() {
+        for dependency in attachable.depends_on.iter() {
+            if !index.contains_key(dependency.as_str()) && !attached.contains(dependency) {
+                return Err(Error::InvalidService(f!(
+                    "{} depends on {}, which is neither in this file nor attached",
+                    attachable.name,
+                    dependency
+                )));
+            }
+        }
+    }
+
+    let refs: Vec<&Attachable> = attachables.iter().collect();
+    let mut marks: Vec<Option<Mark>> = vec![None; attachables.len()];
+    let mut order: Vec<usize> = Vec::with_capacity(attachables.len());
+    let mut path: Vec<usize> = vec![];
+    for i in 0..attachables.len() {
+        visit(i, &refs, &index, &mut marks, &mut path, &mut order)?;
+    }
+
+    let mut slots: Vec<Option<Attachable>> = attachables.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .map(|i| slots[i].take().unwrap())
+        .collect())
+}
+
+// Names of the attached services in the order they have to be stopped, the reverse of
+// the startup order: a service goes before the ones it depends on
+pub fn shutdown_order(attachables: &[&Attachable]) -> Vec<String> {
+    let index: HashMap<&str, usize> = attachables
+        .iter()
+        .enumerate()
+        .map(|(i, attachable)| (attachable.name.as_str(), i))
+        .collect();
+
+    let mut marks: Vec<Option<Mark>> = vec![None; attachables.len()];
+    let mut order: Vec<usize> = Vec::with_capacity(attachables.len());
+    let mut path: Vec<usize> = vec![];
+    for i in 0..attachables.len() {
+        if visit(i, attachables, &index, &mut marks, &mut path, &mut order).is_err() {
+            path.clear();
+        }
+    }
+
+    let mut names: Vec<String> = order
+        .into_iter()
+        .rev()
+        .map(|i| attachables[i].name.clone())
+        .collect();
+    // Attached services can't form a cycle, but should they, stop them last anyway
+    for attachable in attachables.iter() {
+        if !names.contains(&attachable.name) {
+            names.push(attachable.name.clone());
+        }
+    }
+    names
+}
+
+// Depth first, a service is pushed to `order` once all of its dependencies are.
+// `path` is the chain being visited, used to spell out a cycle when we run into one
+fn visit(
+    i: usize,
+    attachables: &[&Attachable],
+    index: &HashMap<&str, usize>,
+    marks: &mut Vec<Option<Mark>>,
+    path: &mut Vec<usize>,
+    order: &mut Vec<usize>,
+) -> Result<()> {
+    match marks[i] {
+        Some(Mark::Done) => return Ok(()),
+        Some(Mark::Visiting) => {
+            let start = path.iter().position(|&p| p == i).unwrap();
+            let cycle: Vec<&str> = path[start..]
+                .iter()
+                .chain(std::iter::once(&i))
+                .map(|&p| attachables[p].name.as_str())
+                .collect();
+            return Err(Error::DependencyCycle(cycle.join(" -> ")));
+        }
+        None => {}
+    }
+
+    marks[i] = Some(Mark::Visiting);
+    path.push(i);
+    for dependency in attachables[i].depends_on.iter() {
+        // Already attached services aren't part of the batch
+        if let Some(&d) = index.get(dependency.as_str()) {
+            visit(d, attachables, index, marks, path, order)?;
+        }
+    }
+    path.pop();
+    marks[i] = Some(Mark::Done);
+    order.push(i);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn service(name: &str, depends_on: &[&str]) -> Attachable {
+        let mut attachable = Attachable::new(
+            name.to_string(),
+            "true".to_string(),
+            vec![],
+            PathBuf::from("/"),
+            0,
+            0,
+        );
+        attachable.depends_on = depends_on.iter().map(|d| d.to_string()).collect();
+        attachable
+    }
+
+    fn names(attachables: &[Attachable]) -> Vec<&str> {
+        attachables.iter().map(|a| a.name.as_str()).collect()
+    }
+
+    #[test]
+    fn dependencies_start_first() {
+        let batch = vec![
+            service("gateway", &["auth", "db"]),
+            service("auth", &["db"]),
+            service("db", &[]),
+            service("static", &[]),
+        ];
+        let order = startup_order(batch, &HashSet::new()).unwrap();
+        assert_eq!(names(&order), ["db", "auth", "gateway", "static"]);
+    }
+
+    #[test]
+    fn cycles_are_spelled_out() {
+        let batch = vec![
+            service("gateway", &["auth"]),
+            service("auth", &["users"]),
+            service("users", &["auth"]),
+        ];
+        match startup_order(batch, &HashSet::new()) {
+            Err(Error::DependencyCycle(cycle)) => assert_eq!(cycle, "auth -> users -> auth"),
+            other => panic!(
+                "expected a cycle, got {:?}",
+                other.map(|o| names(&o).join(","))
+            ),
+        }
+    }
+
+    #[test]
+    fn dependents_stop_first() {
+        let attached = [
+            service("db", &[]),
+            service("gateway", &["auth", "db"]),
+            service("auth", &["db"]),
+        ];
+        let attached: Vec<&Attachable> = attached.iter().collect();
+        assert_eq!(shutdown_order(&attached), ["gateway", "auth", "db"]);
+    }
+
+    #[test]
+    fn unknown_dependencies_are_rejected() {
+        let batch = vec![service("gateway", &["auth"])];
+        assert!(startup_order(batch, &HashSet::new()).is_err());
+
+        let batch = vec![service("gateway", &["auth"])];
+        let attached = HashSet::from(["auth".to_string()]);
+        assert_eq!(
+            names(&startup_order(batch, &attached).unwrap()),
+            ["gateway"]
+        );
+    }
+
+    #[test]
+    fn attached_names_are_rejected() {
+        let batch = vec![service("auth", &[]), service("gateway", &["auth"])];
+        let attached = HashSet::from(["auth".to_string()]);
+        assert!(startup_order(batch, &attached).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::prelude::*;
+use std::collections::HashMap;
+
+// Parses the contents of a .env file into its variables, in file order
+//
+//   # comments and blank lines are skipped
+//   export KEY=value         `export` is optional
+//   KEY=value # comment      unquoted values are trimmed, ` #` starts a comment
+//   KEY='literal $value'     single quotes keep everything as is
+//   KEY="a\nb ${OTHER}"      double quotes understand escapes, both quote kinds may span lines
+//
+// $VAR, ${VAR} and ${VAR:-default} are expanded in unquoted and double quoted values,
+// from the variables above in the same file first, then from `lookup`
+pub fn parse(
+    contents: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> core::result::Result<Vec<(String, String)>, String> {
+    let mut vars: Vec<(String, String)> = vec![];
+    let mut defined: HashMap<String, String> = HashMap::new();
+    let mut lines = contents.lines().enumerate();
+
+    while let Some((i, line)) = lines.next() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+
+        let (key, rest) = line
+            .split_once('=')
+            .ok_or_else(|| f!("line {}: expected KEY=value", line_no))?;
+        let key = key.trim();
+        if !is_valid_key(key) {
+            return Err(f!("line {}: invalid variable name {:?}", line_no, key));
+        }
+        let rest = rest.trim_start();
+
+        let resolve = |name: &str| defined.get(name).cloned().or_else(|| lookup(name));
+        let value = match rest.chars().next() {
+            Some(quote @ ('\'' | '"')) => {
+                // Pull in following lines until the closing quote shows up
+                let mut raw = rest[1..].to_string();
+                let end = loop {
+                    if let Some(end) = closing_quote(&raw, quote) {
+                        break end;
+                    }
+                    let (_, next) = lines
+                        .next()
+                        .ok_or_else(|| f!("line {}: unterminated {} quote", line_no, quote))?;
+                    raw.push('\n');
+                    raw.push_str(next);
+                };
+                let trailing = raw[end + 1..].trim();
+                if !trailing.is_empty() && !trailing.starts_with('#') {
+                    return Err(f!(
+                        "line {}: unexpected {:?} after the value",
+                        line_no,
+                        trailing
+                    ));
+                }
+                match quote {
+                    '\'' => raw[..end].to_string(),
+                    _ => expand(&unescape(&raw[..end]), &resolve),
+                }
+            }
+            _ => {
+                let value = match rest.find(" #").or_else(|| rest.find("\t#")) {
+                    Some(comment) => &rest[..comment],
+                    None => rest,
+                };
+                expand(value.trim(), &resolve)
+            }
+        };
+
+        defined.insert(key.to_string(), value.clone());
+        vars.push((key.to_string(), value));
+    }
+
+    Ok(vars)
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// Position of the first quote that isn't escaped, only double quotes have escapes
+fn closing_quote(raw: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in raw.char_indices() {
+        match c {
+            '\\' if quote == '"' && !escaped => escaped = true,
+            c if c == quote && !escaped => return Some(i),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+// \$ and \\ are kept escaped for expand to resolve, so an escaped dollar stays a dollar
+fn unescape(raw: &str) -> String {
+    let mut value = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            Some('t') => value.push('\t'),
+            Some('$') => value.push_str("\\$"),
+            Some('\\') => value.push_str("\\\\"),
+            Some(c) => value.push(c),
+            None => value.push('\\'),
+        }
+    }
+    value
+}
+
+fn expand(value: &str, lookup: &impl Fn(&str) -> Option<String>) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(i) = rest.find(['$', '\\']) {
+        expanded.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+
+        if rest[i..].starts_with("\\$") || rest[i..].starts_with("\\\\") {
+            expanded.push_str(&after[..1]);
+            rest = &after[1..];
+        } else if rest[i..].starts_with('\\') {
+            expanded.push('\\');
+            rest = after;
+        } else if let Some(braced) = after.strip_prefix('{') {
+            let Some(end) = braced.find('}') else {
+                // Not a reference after all
+                expanded.push('$');
+                rest = after;
+                continue;
+            };
+            let reference = &braced[..end];
+            let value = match reference.split_once(":-") {
+                Some((name, default)) => lookup(name)
+                    .filter(|value| !value.is_empty())
+                    .unwrap_or_else(|| default.to_string()),
+                None => lookup(reference).unwrap_or_default(),
+            };
+            expanded.push_str(&value);
+            rest = &braced[end + 1..];
+        } else {
+            let len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            if len == 0 {
+                expanded.push('$');
+            } else {
+                expanded.push_str(&lookup(&after[..len]).unwrap_or_default());
+            }
+            rest = &after[len..];
+        }
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_with_home(contents: &str) -> Vec<(String, String)> {
+        parse(contents, |name| {
+            (name == "HOME").then(|| "/home/dev".to_string())
+        })
+        .unwrap()
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn parses_dotenv_syntax() {
+        let vars = parse_with_home(
+            "# database\n\
+             export DB_HOST=localhost # local only\n\
+             DB_URL=postgres://${DB_HOST}:5432/app\n\
+             \n\
+             LITERAL='no $DB_HOST here'\n\
+             GREETING=\"hello\\n\\\"world\\\" \\$5\"\n\
+             WIN_PATH=\"C:\\\\$HOME\"\n\
+             CACHE=$HOME/.cache\n\
+             LEVEL=${LOG_LEVEL:-info}\n\
+             KEY=\"-----BEGIN\n\
+             abc\n\
+             -----END\"\n",
+        );
+        assert_eq!(
+            vars,
+            [
+                pair("DB_HOST", "localhost"),
+                pair("DB_URL", "postgres://localhost:5432/app"),
+                pair("LITERAL", "no $DB_HOST here"),
+                pair("GREETING", "hello\n\"world\" $5"),
+                pair("WIN_PATH", "C:\\/home/dev"),
+                pair("CACHE", "/home/dev/.cache"),
+                pair("LEVEL", "info"),
+                pair("KEY", "-----BEGIN\nabc\n-----END"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_the_offending_line() {
+        let lookup = |_: &str| None;
+        assert_eq!(
+            parse("A=1\nnot a var\n", lookup).unwrap_err(),
+            "line 2: expected KEY=value"
+        );
+        assert!(parse("A=\"open\n", lookup)
+            .unwrap_err()
+            .contains("unterminated"));
+        assert!(parse("1A=x", lookup).is_err());
+    }
+}
@@ -1,8 +1,114 @@
+use packet::{CodecError, ErrorCode};
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Generic {0}")]
     Generic(String),
 
+    #[error("Unsupported wire format version {0:#04x} (expected {expected:#04x}), the client needs to be upgraded", expected = packet::WIRE_VERSION)]
+    UnsupportedWireVersion(u8),
+
+    #[error("Handshake rejected: bad magic bytes {0:x?}")]
+    BadHandshakeMagic([u8; 4]),
+
+    #[error("Handshake rejected: protocol version {0} is outside the supported range {min}..={max}", min = packet::MIN_PROTOCOL_VERSION, max = packet::PROTOCOL_VERSION)]
+    UnsupportedProtocolVersion(u16),
+
+    #[error("Handshake not received within {0:?}")]
+    HandshakeTimeout(std::time::Duration),
+
+    #[error("Frame of {0} bytes exceeds the {max} byte limit", max = packet::codec::MAX_FRAME_SIZE)]
+    FrameTooLarge(u32),
+
+    #[error("Malformed packet: {0}")]
+    Packet(#[from] deku::DekuError),
+
+    #[error("Unknown packet id {0:#04x}")]
+    UnknownPacket(u8),
+
+    #[error("Invalid service definition: {0}")]
+    InvalidService(String),
+
+    #[error("Dependency cycle: {0}")]
+    DependencyCycle(String),
+
+    #[error("No attached service named or identified by {0}")]
+    UnknownService(String),
+
+    #[error("{0} did not become ready: {1}")]
+    NotReady(String, String),
+
+    #[error("{failed} of {total} services failed to start\n{summary}")]
+    BatchFailed {
+        failed: usize,
+        total: usize,
+        summary: String,
+    },
+
+    #[error("{0} can't use port {1}, it is held by {2}")]
+    PortInUse(String, u16, String),
+
+    #[error("{0} did not stop: {1}")]
+    StopFailed(String, String),
+
+    #[error("The server is shutting down")]
+    ShuttingDown,
+
+    #[error("Failed to spawn {0}: {1}")]
+    Spawn(String, std::io::Error),
+
+    #[error("Failed to open lua services file {0}: {1}")]
+    LuaFile(String, std::io::Error),
+
+    #[error("Failed to load env file {0}: {1}")]
+    EnvFile(String, String),
+
+    #[error("Failed to open log file {0}: {1}")]
+    LogFile(String, std::io::Error),
+
+    #[error(transparent)]
+    Lua(#[from] rlua::Error),
+
     #[error(transparent)]
     IO(#[from] std::io::Error),
 }
+
+impl From<CodecError> for Error {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::Io(e) => Error::IO(e),
+            CodecError::UnsupportedWireVersion(version) => Error::UnsupportedWireVersion(version),
+            CodecError::FrameTooLarge(size) => Error::FrameTooLarge(size),
+            CodecError::Malformed(e) => Error::Packet(e),
+        }
+    }
+}
+
+impl Error {
+    // Code sent to clients alongside the message in Response::Error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Generic(_) => ErrorCode::Generic,
+            Error::UnsupportedWireVersion(_)
+            | Error::BadHandshakeMagic(_)
+            | Error::UnsupportedProtocolVersion(_)
+            | Error::HandshakeTimeout(_)
+            | Error::FrameTooLarge(_)
+            | Error::Packet(_) => ErrorCode::MalformedPacket,
+            Error::UnknownPacket(_) => ErrorCode::UnknownPacket,
+            Error::InvalidService(_) | Error::DependencyCycle(_) | Error::EnvFile(..) => {
+                ErrorCode::InvalidService
+            }
+            Error::UnknownService(_) => ErrorCode::UnknownService,
+            Error::NotReady(..) => ErrorCode::NotReady,
+            Error::BatchFailed { .. } => ErrorCode::BatchFailed,
+            Error::PortInUse(..) => ErrorCode::PortInUse,
+            Error::StopFailed(..) => ErrorCode::StopFailed,
+            Error::ShuttingDown => ErrorCode::ShuttingDown,
+            Error::Spawn(..) => ErrorCode::SpawnFailed,
+            Error::LuaFile(..) => ErrorCode::LuaFile,
+            Error::Lua(_) => ErrorCode::Lua,
+            Error::LogFile(..) | Error::IO(_) => ErrorCode::Io,
+        }
+    }
+}
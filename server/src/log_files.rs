@@ -0,0 +1,151 @@
+use crate::prelude::*;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_KEEP: usize = 5;
+
+// Where and how much of a service's output is kept on disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFileConfig {
+    pub dir: PathBuf,
+    // Size a file may grow to before it is rotated
+    pub max_size: u64,
+    // Rotated files kept around, <name>.log.1 being the most recent
+    pub keep: usize,
+}
+
+// <dir>/<name>.log, rotated to <name>.log.1 ..= <name>.log.<keep> once it outgrows
+// max_size
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(name: &str, config: &LogFileConfig) -> Result<RotatingFile> {
+        let path = config.dir.join(f!("{}.log", name));
+        let open_error = |e| Error::LogFile(path.display().to_string(), e);
+        fs::create_dir_all(&config.dir).map_err(open_error)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(open_error)?;
+        let size = file.metadata().map_err(open_error)?.len();
+
+        Ok(RotatingFile {
+            path,
+            config: config.clone(),
+            file,
+            size,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.config.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |i: usize| PathBuf::from(f!("{}.{}", self.path.display(), i));
+        if self.config.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Shift every file up by one, the oldest falls off the end
+            for i in (1..self.config.keep).rev() {
+                if rotated(i).exists() {
+                    fs::rename(rotated(i), rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+// e.g. 2023-02-14T09:26:53.120Z
+pub fn format_utc(at: SystemTime) -> String {
+    let ms = at
+        .duration_since(UNIX_EPOCH)
+        .map(|at| at.as_millis() as u64)
+        .unwrap_or(0);
+    let days = (ms / 86_400_000) as i64;
+
+    // Days since the epoch to a civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    f!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        ms / 3_600_000 % 24,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let at = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(format_utc(at), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn rotates_and_keeps_the_newest_files() {
+        let dir = std::env::temp_dir().join(f!("localmesh-log-files-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = LogFileConfig {
+            dir: dir.clone(),
+            max_size: 8,
+            keep: 2,
+        };
+
+        let mut file = RotatingFile::open("api", &config).unwrap();
+        for line in ["one", "two", "three", "four", "five"] {
+            file.write_line(line).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("api.log"), "five\n");
+        assert_eq!(read("api.log.1"), "four\n");
+        assert_eq!(read("api.log.2"), "three\n");
+        assert!(!dir.join("api.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,41 +1,117 @@
 use crate::prelude::*;
 use env_logger::{self, Env};
 use lazy_static::lazy_static;
-use log::info;
-use packet::{Message, PacketHeader};
-use std::{collections::HashMap, io::Read, io::Write, net::TcpListener, sync::RwLock};
+use log::{error, info, warn};
+use service_attacher::ServiceAttacher;
+use std::{collections::HashMap, process::ExitStatus, sync::RwLock, time::Duration};
+use tokio::{
+    signal::{
+        self,
+        unix::{signal, SignalKind},
+    },
+    sync::mpsc,
+    task,
+};
 mod error;
+// `W` is unused for now
+#[allow(dead_code)]
 mod prelude;
 
+mod control_server;
+mod dependencies;
+mod dotenv;
+// The first router, the proxy took over and nothing calls it anymore
+#[allow(dead_code, unused_imports, clippy::all)]
 mod http_router;
+mod log_files;
 mod message_parser;
+mod ports;
+mod process_tree;
+mod proxy;
+mod readiness;
 mod service_attacher;
+mod service_logs;
 
-fn main() -> Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+// How long the client that sent Shutdown gets to receive the summary
+const SHUTDOWN_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[tokio::main]
+async fn main() -> Result<()> {
     env_logger::init_from_env(Env::default().default_filter_or("debug"));
-    let mut buffer = [0; 1024];
-    for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        info!("Connection established");
-
-        let data = stream.read(&mut buffer).unwrap();
-        let packet_header = PacketHeader::try_from(&buffer[0..2]).unwrap();
-        let message_size = std::mem::size_of::<PacketHeader>() + packet_header.data_size as usize;
-
-        // Extract only the valid parts of the buffer (exclude trailing 0's)
-        let valid_buffer = &buffer[0..message_size];
-        message_parser::parse_message(Message::try_from(valid_buffer).unwrap());
-        stream.write(b"ok!").unwrap();
+    service_attacher::spawn_reaper(&SERVICE_ATTACHER);
+    {
+        let mut service_attacher = SERVICE_ATTACHER.write().unwrap();
+        let routes = service_attacher.routes.clone();
+        service_attacher.http_server_handle = Some(proxy::start("127.0.0.1:9000", routes)?);
     }
 
-    Ok(())
+    let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel();
+    let mut terminate = signal(SignalKind::terminate())?;
+    let request = tokio::select! {
+        result = control_server::run("127.0.0.1:8080", shutdown_tx) => return result,
+        _ = signal::ctrl_c() => {
+            info!("Received SIGINT");
+            None
+        }
+        _ = terminate.recv() => {
+            info!("Received SIGTERM");
+            None
+        }
+        request = shutdown_rx.recv() => request,
+    };
+    // The control server is gone with the select, no new connections from here on, and
+    // Shutdown packets still on their way get refused
+    drop(shutdown_rx);
+    info!("Shutting down");
+
+    let mut stopping = task::spawn_blocking(|| ServiceAttacher::shutdown(&SERVICE_ATTACHER));
+    let stopped = tokio::select! {
+        stopped = &mut stopping => stopped.map_err(|e| Error::Generic(e.to_string()))?,
+        // Asked twice, don't sit through the grace periods
+        _ = signal::ctrl_c() => force_exit(),
+        _ = terminate.recv() => force_exit(),
+    };
+
+    let (summary, failed) = summarize(&stopped);
+    info!("{}", summary);
+    if let Some(request) = request {
+        let _ = request.summary.send(summary);
+        let _ = tokio::time::timeout(SHUTDOWN_REPLY_TIMEOUT, request.sent).await;
+    }
+    // Blocking commands may still be waiting on readiness probes, don't wait for them
+    std::process::exit(if failed { 1 } else { 0 });
+}
+
+fn force_exit() -> ! {
+    warn!("Asked to shut down again, exiting without stopping the remaining services");
+    std::process::exit(1);
+}
+
+// One line per service, in the order they were stopped. Also tells whether any of them
+// could not be stopped
+fn summarize(stopped: &[(String, Result<Option<ExitStatus>>)]) -> (String, bool) {
+    let mut failed = false;
+    let mut lines = vec![f!("Stopped {} services", stopped.len())];
+    for (name, result) in stopped {
+        match result {
+            Ok(Some(status)) => lines.push(f!("{}: {}", name, status)),
+            Ok(None) => lines.push(f!("{}: was not running", name)),
+            Err(e) => {
+                error!("{}", e);
+                failed = true;
+                lines.push(f!("{}: failed, {}", name, e));
+            }
+        }
+    }
+    (lines.join("\n"), failed)
 }
 
 lazy_static! {
     static ref SERVICE_ATTACHER: RwLock<service_attacher::ServiceAttacher> =
         RwLock::new(service_attacher::ServiceAttacher {
             services: HashMap::new(),
+            routes: proxy::RouteTable::default(),
             http_server_handle: None,
+            shutting_down: false,
         });
 }
@@ -1,85 +1,487 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
 
+use crate::dotenv;
+use crate::log_files::{LogFileConfig, DEFAULT_KEEP, DEFAULT_MAX_SIZE};
+use crate::prelude::*;
+use crate::process_tree;
+use crate::readiness::{Readiness, ReadinessProbe, DEFAULT_READY_TIMEOUT};
+use crate::service_attacher::{
+    AttachOutcome, Attachable, Progress, ServiceAttacher, DEFAULT_MAX_PARALLEL,
+};
+use crate::service_logs::LogFilter;
 use crate::SERVICE_ATTACHER;
-use crate::{prelude::*, service_attacher::Attachable};
+use deku::prelude::*;
 use log::debug;
-use packet::{Message, PacketId, Service, LuaServices};
-use rlua::{Lua, Table};
+use packet::{
+    DetachService, GetLogs, LogLine, LogLines, LogStream, LuaServices, Message, PacketId, Service,
+    SubscribeLogs,
+};
+use regex::Regex;
+use rlua::{FromLua, Lua, Table};
+
+// GetLogs replies keep the newest lines that fit in a frame, with room to spare for the
+// headers around them
+const MAX_LOG_REPLY_SIZE: usize = packet::codec::MAX_FRAME_SIZE as usize - 64 * 1024;
+
+// at_unix_ms, stream and line_len in front of every line
+const LOG_LINE_HEADER_SIZE: usize = 13;
 // Message is the most primitive type, it simply takes an ID and a blob of data
 // Here, let's parse the message into something meaningful
-pub fn parse_message(msg: Message) {
+//
+// The returned bytes are sent back to the client as the payload of a Response::Ok
+// Runs on a blocking thread, `progress` carries Response::Partial updates to the client
+// while the command is still running
+pub fn parse_message(msg: Message, progress: &Progress) -> Result<Vec<u8>> {
     debug!("Attempt to parse message: {:?}", msg);
-    let cmd = PacketId::try_from(msg.id).unwrap();
+    let cmd = PacketId::try_from(msg.id).map_err(|_| Error::UnknownPacket(msg.id))?;
 
     match cmd {
         PacketId::AttachService => {
-            let service = Service::try_from(&msg.data[..])
-                .map_err(|e| Error::Generic(e.to_string()))
-                .unwrap();
-
-            let attachable = Attachable::try_from(service).unwrap();
+            let service = Service::try_from(&msg.data[..])?;
+            let attachable = Attachable::try_from(service)?;
+            let reply = f!("attached {} ({})", attachable.name, attachable.id);
 
-            let mut service_attacher = SERVICE_ATTACHER.write().unwrap();
-            service_attacher.attach(attachable);
-        },
+            ServiceAttacher::attach(&SERVICE_ATTACHER, attachable)?;
+            Ok(reply.into_bytes())
+        }
         // Loads the services from a lua file
         PacketId::LuaServices => {
-            let lua_services_file = LuaServices::try_from(&msg.data[..]).unwrap();
-            let filepath = std::str::from_utf8(&lua_services_file.filepath[..]).unwrap();
-            let mut lua_file = match File::open(filepath) {
-                Ok(file) => file,
-                Err(e) => {
-                    log::error!("Failed to open file {}", e.to_string());
-                    return;
+            let lua_services_file = LuaServices::try_from(&msg.data[..])?;
+            let filepath = std::str::from_utf8(&lua_services_file.filepath[..])
+                .map_err(|e| Error::InvalidService(e.to_string()))?;
+            let services_file = load_lua_services(filepath)?;
+
+            // Attach all services
+            let outcomes = ServiceAttacher::batch_attach(
+                &SERVICE_ATTACHER,
+                services_file.attachables,
+                services_file.max_parallel,
+                progress,
+            )?;
+            let failed = outcomes
+                .iter()
+                .filter(|(_, outcome)| !matches!(outcome, AttachOutcome::Ready))
+                .count();
+            let summary: Vec<String> = outcomes
+                .iter()
+                .map(|(name, outcome)| f!("{}: {}", name, outcome))
+                .collect();
+            if failed > 0 {
+                return Err(Error::BatchFailed {
+                    failed,
+                    total: outcomes.len(),
+                    summary: summary.join("\n"),
+                });
+            }
+            Ok(summary.join("\n").into_bytes())
+        }
+        PacketId::DetachService => {
+            let detach_service = DetachService::try_from(&msg.data[..])?;
+            let name_or_id = std::str::from_utf8(&detach_service.name_or_id)
+                .map_err(|e| Error::InvalidService(e.to_string()))?;
+
+            let attachable = ServiceAttacher::detach(&SERVICE_ATTACHER, name_or_id)?;
+            let response = match attachable.exit_status {
+                Some(status) => f!(
+                    "detached {} ({}), {}",
+                    attachable.name,
+                    attachable.id,
+                    status
+                ),
+                None => f!("detached {} ({})", attachable.name, attachable.id),
+            };
+            Ok(response.into_bytes())
+        }
+        PacketId::ListServices => {
+            let service_attacher = SERVICE_ATTACHER.read().unwrap();
+            Ok(service_attacher.list()?.to_bytes()?)
+        }
+        PacketId::GetLogs => {
+            let get_logs = GetLogs::try_from(&msg.data[..])?;
+            let name_or_id = std::str::from_utf8(&get_logs.name_or_id)
+                .map_err(|e| Error::InvalidService(e.to_string()))?;
+            let since = match get_logs.since_unix_ms {
+                0 => None,
+                since => Some(UNIX_EPOCH + Duration::from_millis(since)),
+            };
+            let stream = match get_logs.stream {
+                0 => None,
+                stream => {
+                    Some(LogStream::try_from(stream).map_err(|e| Error::Generic(e.to_string()))?)
                 }
             };
 
-            let mut lua_script_contents = String::new();
-            lua_file.read_to_string(&mut lua_script_contents).unwrap();
-
-            drop(lua_file); // No longer needed
-            let lua = Lua::new();
-
-            // TODO: works for now, organize later!
-            lua.context(|ctx| {
-                ctx.load(&lua_script_contents).set_name(filepath.clone()).unwrap().exec().unwrap();
-                let globals = ctx.globals();
-                let services = globals.get::<_, Table>("Services").unwrap();
-                
-                let mut service_attacher = SERVICE_ATTACHER.write().unwrap();
-                // Parsing Lua services. TODO: Better error handling / reporting
-                // Avoid just unwraping here...actually handle nils from lua and spit meaningful
-                // errors
-
-                let mut attachables: Vec<Attachable> = vec![];
-                for item in services.pairs::<rlua::Value, rlua::Table>(){
-                    let (_, service) = item.unwrap();
-                    let service_name = service.get::<_, String>("name").unwrap();
-                    let path = service.get::<_, String>("path").unwrap();
-                    let port = service.get::<_, u16>("port").unwrap();
-                    let service_type = service.get::<_, u8>("service_type").unwrap();
-                    let cmd = service.get::<_, String>("command").unwrap();
-
-                    // Extract command_args (lua table) into the args vector...is there a better
-                    // way to do this?                   
-                    let cmd_args = service.get::<_, Table>("command_args").unwrap();
-                    let mut args: Vec<std::string::String> = vec![];
-                    for i in 1..=cmd_args.len().unwrap() {
-                        args.push(cmd_args.get::<_, String>(i).unwrap());
-                    }
-
-                    let attachable = Attachable::new(service_name.clone(), cmd.clone(), args.clone(), PathBuf::from(path.clone()), service_type, port); 
-                    attachables.push(attachable);
-                    debug!("service_name: {}, path: {}, port: {}, cmd: {}, args: {:?}, service_type: {}", service_name, path, port, cmd, args, service_type);
+            let logs = SERVICE_ATTACHER
+                .read()
+                .unwrap()
+                .find(name_or_id)?
+                .logs
+                .clone();
+            let logs = logs.lock().unwrap();
+            let mut lines: Vec<LogLine> = vec![];
+            let mut size = 0;
+            for entry in logs
+                .query(get_logs.tail as usize, since, stream)
+                .into_iter()
+                .rev()
+            {
+                size += LOG_LINE_HEADER_SIZE + entry.line.len();
+                if size > MAX_LOG_REPLY_SIZE {
+                    break;
                 }
-                // Attach all services
-                service_attacher.batch_attach(attachables);
+                lines.push(entry.to_packet());
+            }
+            lines.reverse();
+            Ok(LogLines {
+                count: lines.len() as u32,
+                lines,
+            }
+            .to_bytes()?)
+        }
+        // Served by the control server itself, see control_server::follow_logs
+        PacketId::SubscribeLogs | PacketId::UnsubscribeLogs => Err(Error::Generic(
+            "Not following any logs on this connection".to_string(),
+        )),
+        PacketId::LogEvent => Err(Error::Generic(
+            "LogEvent packets can only be sent by the server".to_string(),
+        )),
+        PacketId::Response => Err(Error::Generic(
+            "Response packets can only be sent by the server".to_string(),
+        )),
+        // Needs main to stop the server afterwards, see control_server::request_shutdown
+        PacketId::Shutdown => Err(Error::Generic(
+            "Shutdown is handled by the control server".to_string(),
+        )),
+    }
+}
 
-            });
+// Everything a lua services file defines
+struct ServicesFile {
+    attachables: Vec<Attachable>,
+    // `MaxParallel` global, how many services may be starting at once
+    max_parallel: usize,
+}
+
+// Resolves the services of a SubscribeLogs, ids are turned into names since that is
+// what log lines are tagged with
+pub fn log_filter(msg: Message) -> Result<LogFilter> {
+    let subscribe = SubscribeLogs::try_from(&msg.data[..])?;
+    let stream = match subscribe.stream {
+        0 => None,
+        stream => Some(LogStream::try_from(stream).map_err(|e| Error::Generic(e.to_string()))?),
+    };
+    if subscribe.services.is_empty() {
+        return Ok(LogFilter {
+            services: None,
+            stream,
+        });
+    }
 
-        },
-        PacketId::DetachService => {}
+    let service_attacher = SERVICE_ATTACHER.read().unwrap();
+    let mut services = HashSet::new();
+    for service in subscribe.services.iter() {
+        let name_or_id = std::str::from_utf8(&service.name_or_id)
+            .map_err(|e| Error::InvalidService(e.to_string()))?;
+        services.insert(service_attacher.find(name_or_id)?.name.clone());
     }
+    Ok(LogFilter {
+        services: Some(services),
+        stream,
+    })
+}
+
+fn load_lua_services(filepath: &str) -> Result<ServicesFile> {
+    let mut lua_file = File::open(filepath).map_err(|e| Error::LuaFile(filepath.to_string(), e))?;
+
+    let mut lua_script_contents = String::new();
+    lua_file
+        .read_to_string(&mut lua_script_contents)
+        .map_err(|e| Error::LuaFile(filepath.to_string(), e))?;
+
+    drop(lua_file); // No longer needed
+    let lua = Lua::new();
+
+    // TODO: works for now, organize later!
+    lua.context(|ctx| {
+        ctx.load(&lua_script_contents).set_name(filepath)?.exec()?;
+        let globals = ctx.globals();
+        let services = lua_field::<Table>(&globals, "<globals>", "Services")?;
+        let max_parallel = lua_field::<Option<usize>>(&globals, "<globals>", "MaxParallel")?
+            .unwrap_or(DEFAULT_MAX_PARALLEL)
+            .max(1);
+        // e.g. Logs = { dir = "/var/log/localmesh", max_size = 10485760, keep = 5 }
+        let log_file = match lua_field::<Option<Table>>(&globals, "<globals>", "Logs")? {
+            Some(logs) => Some(parse_log_file(&logs, "<globals>", None)?),
+            None => None,
+        };
+
+        let mut attachables: Vec<Attachable> = vec![];
+        for item in services.pairs::<rlua::Value, rlua::Table>() {
+            let (_, service) = item?;
+            let service_name = lua_field::<String>(&service, "<unnamed>", "name")?;
+            let path = lua_field::<String>(&service, &service_name, "path")?;
+            // port = "auto" (or 0) picks a free one when the service is spawned
+            let port = match lua_field::<rlua::Value>(&service, &service_name, "port")? {
+                rlua::Value::String(port) if port.as_bytes() == b"auto" => 0,
+                _ => lua_field::<u16>(&service, &service_name, "port")?,
+            };
+            let service_type = lua_field::<u8>(&service, &service_name, "service_type")?;
+            let cmd = lua_field::<String>(&service, &service_name, "command")?;
+
+            // Extract command_args (lua table) into the args vector...is there a better
+            // way to do this?
+            let cmd_args = lua_field::<Table>(&service, &service_name, "command_args")?;
+            let mut args: Vec<std::string::String> = vec![];
+            for i in 1..=cmd_args.len()? {
+                args.push(cmd_args.get::<_, String>(i)?);
+            }
+
+            debug!(
+                "service_name: {}, path: {}, port: {}, cmd: {}, args: {:?}, service_type: {}",
+                service_name, path, port, cmd, args, service_type
+            );
+            let mut attachable = Attachable::new(
+                service_name.clone(),
+                cmd,
+                args,
+                PathBuf::from(path),
+                service_type,
+                port,
+            );
+
+            // Optional supervision settings, see RestartConfig for the defaults
+            let restart_policy = &mut attachable.restart_policy;
+            if let Some(mode) = lua_field::<Option<String>>(&service, &service_name, "restart")? {
+                restart_policy.mode = mode
+                    .parse()
+                    .map_err(|e| Error::InvalidService(f!("{}: {}", service_name, e)))?;
+            }
+            if let Some(max_restarts) =
+                lua_field::<Option<u16>>(&service, &service_name, "max_restarts")?
+            {
+                restart_policy.max_restarts = max_restarts;
+            }
+            if let Some(window) =
+                lua_field::<Option<u64>>(&service, &service_name, "restart_window")?
+            {
+                restart_policy.window = Duration::from_secs(window);
+            }
+            if let Some(delay) = lua_field::<Option<f64>>(&service, &service_name, "restart_delay")?
+            {
+                restart_policy.delay =
+                    Duration::try_from_secs_f64(delay.max(0.0)).map_err(|e| {
+                        Error::InvalidService(f!(
+                            "{}: bad restart_delay {} ({})",
+                            service_name,
+                            delay,
+                            e
+                        ))
+                    })?;
+            }
+
+            // e.g. ready = { http = "/health", status = 200, timeout = 30 }
+            if let Some(ready) = lua_field::<Option<Table>>(&service, &service_name, "ready")? {
+                attachable.readiness = parse_readiness(&ready, &service_name)?;
+            }
+
+            // Variable the port is passed in, PORT by default for automatic ports
+            if let Some(port_env) =
+                lua_field::<Option<String>>(&service, &service_name, "port_env")?
+            {
+                attachable.port_env = Some(port_env);
+            }
+
+            attachable.kill_port_holder =
+                lua_field::<Option<bool>>(&service, &service_name, "kill_port_holder")?
+                    .unwrap_or(false);
+
+            // e.g. stop_signal = "SIGINT", stop_timeout = 30. SIGKILL follows once the
+            // timeout (in seconds) is over
+            if let Some(signal) =
+                lua_field::<Option<String>>(&service, &service_name, "stop_signal")?
+            {
+                attachable.stop_signal = process_tree::parse_signal(&signal).ok_or_else(|| {
+                    Error::InvalidService(f!("{}: unknown stop_signal {}", service_name, signal))
+                })?;
+            }
+            if let Some(timeout) =
+                lua_field::<Option<f64>>(&service, &service_name, "stop_timeout")?
+            {
+                attachable.stop_timeout =
+                    Duration::try_from_secs_f64(timeout.max(0.0)).map_err(|e| {
+                        Error::InvalidService(f!(
+                            "{}: bad stop_timeout {} ({})",
+                            service_name,
+                            timeout,
+                            e
+                        ))
+                    })?;
+            }
+
+            // e.g. env_file = ".env" or { ".env", ".env.local" },
+            // env = { NODE_ENV = "production", PORT = 3000 }, clear_env = true
+            //
+            // Later sources win: the server's own environment (unless clear_env), then
+            // the env files in the order they are listed, then env
+            attachable.clear_env =
+                lua_field::<Option<bool>>(&service, &service_name, "clear_env")?.unwrap_or(false);
+            let mut env: BTreeMap<String, String> = BTreeMap::new();
+            let env_files = match lua_field::<rlua::Value>(&service, &service_name, "env_file")? {
+                rlua::Value::Nil => vec![],
+                rlua::Value::String(file) => vec![file.to_str()?.to_string()],
+                rlua::Value::Table(files) => files
+                    .sequence_values::<String>()
+                    .collect::<rlua::Result<Vec<String>>>()?,
+                _ => {
+                    return Err(Error::InvalidService(f!(
+                        "{}: `env_file` must be a path or a list of paths",
+                        service_name
+                    )))
+                }
+            };
+            for env_file in env_files {
+                let path = attachable.path.join(env_file);
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| Error::EnvFile(path.display().to_string(), e.to_string()))?;
+                let clear_env = attachable.clear_env;
+                let vars = dotenv::parse(&contents, |name| {
+                    env.get(name).cloned().or_else(|| match clear_env {
+                        true => None,
+                        false => std::env::var(name).ok(),
+                    })
+                })
+                .map_err(|e| Error::EnvFile(path.display().to_string(), e))?;
+                env.extend(vars);
+            }
+            if let Some(vars) = lua_field::<Option<Table>>(&service, &service_name, "env")? {
+                for pair in vars.pairs::<String, rlua::Value>() {
+                    let (key, value) = pair?;
+                    let value = env_value(value).ok_or_else(|| {
+                        Error::InvalidService(f!(
+                            "{}: env value of {} must be a string, number or boolean",
+                            service_name,
+                            key
+                        ))
+                    })?;
+                    env.insert(key, value);
+                }
+            }
+            // Sorted, keeps spawns reproducible
+            attachable.env = env.into_iter().collect();
+
+            // Same keys as the global Logs table, missing ones are taken from there.
+            // `logs = false` keeps the service off the disk
+            attachable.log_file = match lua_field::<rlua::Value>(&service, &service_name, "logs")? {
+                rlua::Value::Nil => log_file.clone(),
+                rlua::Value::Boolean(false) => None,
+                rlua::Value::Table(logs) => {
+                    Some(parse_log_file(&logs, &service_name, log_file.as_ref())?)
+                }
+                _ => {
+                    return Err(Error::InvalidService(f!(
+                        "{}: `logs` must be a table or false",
+                        service_name
+                    )))
+                }
+            };
+
+            // e.g. depends_on = { "auth", "db" }
+            if let Some(depends_on) =
+                lua_field::<Option<Table>>(&service, &service_name, "depends_on")?
+            {
+                for i in 1..=depends_on.len()? {
+                    attachable.depends_on.push(depends_on.get::<_, String>(i)?);
+                }
+            }
+
+            attachables.push(attachable);
+        }
+        Ok(ServicesFile {
+            attachables,
+            max_parallel,
+        })
+    })
+}
+
+// A ready table holds exactly one of `tcp = true`, `http = "<path>"` (with an optional
+// `status`, 200 by default) or `stdout = "<regex>"`, plus an optional `timeout` in seconds
+fn parse_readiness(ready: &Table, service_name: &str) -> Result<Readiness> {
+    let probe = if let Some(path) = lua_field::<Option<String>>(ready, service_name, "http")? {
+        let status = lua_field::<Option<u16>>(ready, service_name, "status")?;
+        ReadinessProbe::Http {
+            path,
+            status: status.unwrap_or(200),
+        }
+    } else if let Some(pattern) = lua_field::<Option<String>>(ready, service_name, "stdout")? {
+        let regex = Regex::new(&pattern).map_err(|e| {
+            Error::InvalidService(f!("{}: bad `ready.stdout` regex ({})", service_name, e))
+        })?;
+        ReadinessProbe::Stdout(regex)
+    } else if lua_field::<Option<bool>>(ready, service_name, "tcp")? == Some(true) {
+        ReadinessProbe::Tcp
+    } else {
+        return Err(Error::InvalidService(f!(
+            "{}: `ready` needs one of tcp, http or stdout",
+            service_name
+        )));
+    };
+
+    let timeout = match lua_field::<Option<f64>>(ready, service_name, "timeout")? {
+        Some(timeout) => Duration::try_from_secs_f64(timeout.max(0.0)).map_err(|e| {
+            Error::InvalidService(f!(
+                "{}: bad ready timeout {} ({})",
+                service_name,
+                timeout,
+                e
+            ))
+        })?,
+        None => DEFAULT_READY_TIMEOUT,
+    };
+    Ok(Readiness { probe, timeout })
+}
+
+fn env_value(value: rlua::Value) -> Option<String> {
+    match value {
+        rlua::Value::String(value) => value.to_str().ok().map(|value| value.to_string()),
+        rlua::Value::Integer(value) => Some(value.to_string()),
+        rlua::Value::Number(value) => Some(value.to_string()),
+        rlua::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn parse_log_file(
+    logs: &Table,
+    service_name: &str,
+    defaults: Option<&LogFileConfig>,
+) -> Result<LogFileConfig> {
+    let dir = match lua_field::<Option<String>>(logs, service_name, "dir")? {
+        Some(dir) => PathBuf::from(dir),
+        None => defaults
+            .map(|defaults| defaults.dir.clone())
+            .ok_or_else(|| Error::InvalidService(f!("{}: `logs` needs a dir", service_name)))?,
+    };
+    let max_size = lua_field::<Option<u64>>(logs, service_name, "max_size")?
+        .or(defaults.map(|defaults| defaults.max_size))
+        .unwrap_or(DEFAULT_MAX_SIZE);
+    let keep = lua_field::<Option<usize>>(logs, service_name, "keep")?
+        .or(defaults.map(|defaults| defaults.keep))
+        .unwrap_or(DEFAULT_KEEP);
+    Ok(LogFileConfig {
+        dir,
+        max_size,
+        keep,
+    })
+}
+
+// Reads a field from a lua table, naming the service and the key on failure instead
+// of surfacing rlua's bare conversion error
+fn lua_field<'lua, T: FromLua<'lua>>(table: &Table<'lua>, service: &str, key: &str) -> Result<T> {
+    table
+        .get::<_, T>(key)
+        .map_err(|e| Error::InvalidService(f!("{}: bad or missing `{}` ({})", service, key, e)))
 }
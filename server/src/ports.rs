@@ -0,0 +1,91 @@
+use crate::prelude::*;
+use log::{info, warn};
+use std::{
+    net::TcpListener,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long a killed port holder gets to let go of the port
+const RECLAIM_TIMEOUT: Duration = Duration::from_secs(3);
+const RECLAIM_POLL: Duration = Duration::from_millis(100);
+
+// Any port nobody listens on right now, the OS picks it
+pub fn free_port() -> std::io::Result<u16> {
+    Ok(TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port())
+}
+
+pub fn is_free(port: u16) -> bool {
+    TcpListener::bind(("127.0.0.1", port)).is_ok()
+}
+
+// Makes sure `port` is free before `name` is spawned on it. A holder is only killed
+// when the service opted in with kill_port_holder
+pub fn claim(name: &str, port: u16, kill_holder: bool) -> Result<()> {
+    if is_free(port) {
+        return Ok(());
+    }
+
+    let pids = holders(port);
+    let holder = describe(&pids);
+    if !kill_holder || pids.is_empty() {
+        return Err(Error::PortInUse(name.to_string(), port, holder));
+    }
+
+    warn!("Killing {} to free port {} for {}", holder, port, name);
+    if let Err(e) = port_killer::kill_by_pids(&pids) {
+        warn!("Failed to kill {}: {}", holder, e);
+    }
+    let deadline = Instant::now() + RECLAIM_TIMEOUT;
+    while Instant::now() < deadline {
+        if is_free(port) {
+            info!("Port {} is free again", port);
+            return Ok(());
+        }
+        thread::sleep(RECLAIM_POLL);
+    }
+    Err(Error::PortInUse(
+        name.to_string(),
+        port,
+        f!("{}, even after killing it", holder),
+    ))
+}
+
+// PIDs listening on the port. port-killer only looks them up as part of killing them
+// (and matches clients of the port as well), so ask lsof ourselves. Empty when lsof
+// is missing
+fn holders(port: u16) -> Vec<u32> {
+    let output = Command::new("lsof")
+        .args(["-t", &f!("-iTCP:{}", port), "-sTCP:LISTEN"])
+        .stderr(Stdio::null())
+        .output();
+    let Ok(output) = output else {
+        return vec![];
+    };
+
+    let mut pids: Vec<u32> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|pid| pid.trim().parse().ok())
+        .collect();
+    pids.sort_unstable();
+    pids.dedup();
+    pids
+}
+
+// e.g. "pid 4242 (node)"
+fn describe(pids: &[u32]) -> String {
+    if pids.is_empty() {
+        return "an unknown process".to_string();
+    }
+    let pids: Vec<String> = pids
+        .iter()
+        .map(
+            |pid| match std::fs::read_to_string(f!("/proc/{}/comm", pid)) {
+                Ok(comm) => f!("pid {} ({})", pid, comm.trim()),
+                Err(_) => f!("pid {}", pid),
+            },
+        )
+        .collect();
+    pids.join(", ")
+}
@@ -0,0 +1,194 @@
+use crate::prelude::*;
+use std::{
+    fs, io, thread,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Every service is spawned as the leader of its own process group (pgid == pid), so
+// `npm run debug` and the node process it starts can be signalled together
+pub fn signal_group(pgid: u32, signal: i32) -> io::Result<()> {
+    // A negative pid addresses the whole group
+    match unsafe { libc::kill(-(pgid as i32), signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+pub fn signal(pid: u32, signal: i32) -> io::Result<()> {
+    match unsafe { libc::kill(pid as i32, signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// Sends `signal` to the group and to every one of `pids` that left it, each process
+// gets it once. Failures mean they are gone already
+pub fn signal_tree(pgid: u32, pids: &[u32], signal: i32) {
+    let _ = signal_group(pgid, signal);
+    let processes = processes().unwrap_or_default();
+    for &pid in pids {
+        let in_group = processes
+            .iter()
+            .any(|process| process.pid == pid && process.pgrp == pgid);
+        if !in_group {
+            let _ = self::signal(pid, signal);
+        }
+    }
+}
+
+// Signals a service can be asked to stop with
+const SIGNALS: [(&str, i32); 8] = [
+    ("SIGTERM", libc::SIGTERM),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGWINCH", libc::SIGWINCH),
+];
+
+// "SIGTERM", "TERM", "term" or "15"
+pub fn parse_signal(name: &str) -> Option<i32> {
+    let name = name.trim().to_uppercase();
+    if let Ok(number) = name.parse::<i32>() {
+        return SIGNALS
+            .iter()
+            .find(|(_, signal)| *signal == number)
+            .map(|(_, signal)| *signal);
+    }
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .find(|(signal_name, _)| signal_name[3..] == *name)
+        .map(|(_, signal)| *signal)
+}
+
+pub fn signal_name(signal: i32) -> String {
+    match SIGNALS.iter().find(|(_, number)| *number == signal) {
+        Some((name, _)) => name.to_string(),
+        None => f!("signal {}", signal),
+    }
+}
+
+struct ProcStat {
+    pid: u32,
+    // R, S, Z...
+    state: char,
+    ppid: u32,
+    pgrp: u32,
+}
+
+// Every process on the system, None where there is no /proc (macOS)
+fn processes() -> Option<Vec<ProcStat>> {
+    let entries = fs::read_dir("/proc").ok()?;
+    let stats = entries
+        .filter_map(|entry| {
+            let pid: u32 = entry.ok()?.file_name().to_str()?.parse().ok()?;
+            let stat = fs::read_to_string(f!("/proc/{}/stat", pid)).ok()?;
+            // The command name is in parens and may contain anything, parse after it
+            let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+            Some(ProcStat {
+                pid,
+                state: fields.next()?.chars().next()?,
+                ppid: fields.next()?.parse().ok()?,
+                pgrp: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect();
+    Some(stats)
+}
+
+// Children, grandchildren and so on of `pid`. They are signalled one by one on top of
+// the group since anything can move itself to another group or session
+pub fn descendants(pid: u32) -> Vec<u32> {
+    let processes = processes().unwrap_or_default();
+    let mut found = vec![];
+    let mut parents = vec![pid];
+    while let Some(parent) = parents.pop() {
+        for process in processes.iter().filter(|process| process.ppid == parent) {
+            if !found.contains(&process.pid) {
+                found.push(process.pid);
+                parents.push(process.pid);
+            }
+        }
+    }
+    found
+}
+
+// Processes of the group or of `pids` that are still running. Zombies don't count,
+// they're dead and only waiting for their parent
+pub fn survivors(pgid: u32, pids: &[u32]) -> Vec<u32> {
+    match processes() {
+        Some(processes) => processes
+            .iter()
+            .filter(|process| process.state != 'Z')
+            .filter(|process| process.pgrp == pgid || pids.contains(&process.pid))
+            .map(|process| process.pid)
+            .collect(),
+        // Only the group can be checked
+        None => match signal_group(pgid, 0) {
+            Ok(()) => vec![pgid],
+            Err(_) => vec![],
+        },
+    }
+}
+
+// Polls until nothing of the group or `pids` runs anymore, returns whatever is left
+// after `timeout`
+pub fn wait_gone(pgid: u32, pids: &[u32], timeout: Duration) -> Vec<u32> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = survivors(pgid, pids);
+        if left.is_empty() || Instant::now() >= deadline {
+            return left;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service_attacher::Attachable;
+    use std::path::PathBuf;
+
+    #[test]
+    fn parses_signal_names() {
+        assert_eq!(parse_signal("SIGTERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("int"), Some(libc::SIGINT));
+        assert_eq!(parse_signal("9"), Some(libc::SIGKILL));
+        assert_eq!(parse_signal("SIGNOPE"), None);
+        assert_eq!(parse_signal("999"), None);
+        assert_eq!(signal_name(libc::SIGQUIT), "SIGQUIT");
+    }
+
+    #[test]
+    fn stopping_a_service_stops_what_it_started() {
+        let mut attachable = Attachable::new(
+            "tree".to_string(),
+            "sh".to_string(),
+            vec!["-c".to_string(), "sleep 60 & sleep 60".to_string()],
+            PathBuf::from("/"),
+            0,
+            0,
+        );
+        attachable.stop_timeout = Duration::from_secs(2);
+        attachable.spawn().unwrap();
+        let pgid = attachable.child_process.as_ref().unwrap().id();
+
+        // The shell needs a moment to start the sleeps
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut started = descendants(pgid);
+        while started.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+            started = descendants(pgid);
+        }
+        assert!(!started.is_empty());
+
+        attachable.stop().unwrap();
+        assert_eq!(survivors(pgid, &started), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,229 @@
+use crate::prelude::*;
+use crate::service_attacher::HttpAttachable;
+use actix_web::{
+    dev::ServerHandle,
+    rt,
+    web::{self, Data},
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use log::{debug, info};
+use reqwest::{header, Body, Client};
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, RwLock},
+    thread,
+};
+
+type Routes = HashMap<String, HttpAttachable>;
+
+// Chunks of a request body read ahead of the service
+const PAYLOAD_CHUNKS_IN_FLIGHT: usize = 8;
+
+// Where the proxy sends requests, by the first segment of their path. Replaced as a
+// whole on every change, requests only hold the lock long enough to clone the Arc so
+// an update never waits on them, nor they on it
+#[derive(Clone, Default)]
+pub struct RouteTable(Arc<RwLock<Arc<Routes>>>);
+
+impl RouteTable {
+    pub fn swap(&self, routes: Routes) {
+        let new = Arc::new(routes);
+        let old = std::mem::replace(&mut *self.0.write().unwrap(), new.clone());
+        for (route, service) in new.iter() {
+            if old.get(route).map(|old| old.port) != Some(service.port) {
+                info!(
+                    "requests to http://localhost:9000/{} are now being routed to http://localhost:{}",
+                    route, service.port
+                );
+            }
+        }
+        for route in old.keys().filter(|route| !new.contains_key(*route)) {
+            info!(
+                "requests to http://localhost:9000/{} are no longer routed",
+                route
+            );
+        }
+    }
+
+    fn snapshot(&self) -> Arc<Routes> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+// Runs the proxy on its own thread for as long as the server lives, routes are
+// looked up in `routes` on every request
+pub fn start(addr: &str, routes: RouteTable) -> Result<ServerHandle> {
+    let addr = addr.to_string();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        rt::System::new().block_on(async move {
+            let server = match bind(&addr, routes) {
+                Ok(server) => server,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            info!("HTTP proxy listening on {}", addr);
+            let _ = tx.send(Ok(server.handle()));
+            if let Err(e) = server.await {
+                log::error!("HTTP proxy failed: {}", e);
+            }
+        })
+    });
+    rx.recv()
+        .map_err(|e| Error::Generic(f!("HTTP proxy thread died: {}", e)))?
+}
+
+fn bind(addr: &str, routes: RouteTable) -> Result<actix_web::dev::Server> {
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(Data::new(Client::new()))
+            .app_data(Data::new(routes.clone()))
+            .route("/{tail}*", web::patch().to(forward_request))
+            .route("/{tail}*", web::put().to(forward_request))
+            .route("/{tail}*", web::delete().to(forward_request))
+            .route("/{tail}*", web::get().to(forward_request))
+            .route("/{tail}*", web::post().to(forward_request))
+    })
+    .bind(addr)?
+    // main handles SIGINT and SIGTERM, and stops the proxy as part of the shutdown
+    .disable_signals()
+    .workers(2)
+    .run();
+    Ok(server)
+}
+
+async fn forward_request(
+    client: web::Data<Client>,
+    req: HttpRequest,
+    routes: web::Data<RouteTable>,
+    payload: web::Payload,
+) -> impl Responder {
+    info!("Received a new request, attempting to find a service to route it to");
+    let path_no_leading = req.path().chars().skip(1).collect::<String>(); // Remove the leading / from
+                                                                          // the path
+    let path_parts: Vec<&str> = path_no_leading.split("/").collect();
+
+    debug!("path_stem: {}, path_parts: {:?}", path_parts[0], path_parts);
+    let path_to_forward = if path_parts.len() > 1 {
+        path_parts[1..].join("/")
+    } else {
+        "".to_string()
+    };
+    debug!("path_to_forward: {}", path_to_forward);
+
+    let routes = routes.snapshot();
+    let service_to_forward = match routes.get(path_parts[0]) {
+        Some(service) => service,
+        None => {
+            return HttpResponse::ServiceUnavailable()
+                .body(f!("No service is attached at /{}", path_parts[0]))
+        }
+    };
+    debug!(
+        "Forwarding to {} ({})",
+        service_to_forward.name, service_to_forward.id
+    );
+
+    // Construct request URL, the query string goes along
+    let request_url = match req.query_string() {
+        "" => f!(
+            "http://localhost:{}/{}",
+            service_to_forward.port,
+            path_to_forward
+        ),
+        query => f!(
+            "http://localhost:{}/{}?{}",
+            service_to_forward.port,
+            path_to_forward,
+            query
+        ),
+    };
+
+    let mut reqwest_headers = header::HeaderMap::new();
+    for (header_name, header_value) in req.headers().iter() {
+        if !is_hop_by_hop(header_name) {
+            reqwest_headers.append(header_name.clone(), header_value.clone());
+        }
+    }
+
+    let mut request = client
+        .request(req.method().clone(), request_url)
+        .headers(reqwest_headers);
+    if has_body(&req) {
+        request = request.body(Body::wrap_stream(stream_payload(payload)));
+    }
+
+    let res = match request.send().await {
+        Ok(res) => res,
+        Err(e) => {
+            log::error!("Failed to forward to {}: {}", service_to_forward.name, e);
+            return HttpResponse::BadGateway().body(e.to_string());
+        }
+    };
+
+    let mut response = HttpResponse::build(res.status());
+    for (header_name, header_value) in res.headers().iter() {
+        if !is_hop_by_hop(header_name) && header_name != header::CONTENT_LENGTH {
+            response.append_header((header_name.clone(), header_value.clone()));
+        }
+    }
+    // Passed on as is instead of switching the client to chunked encoding
+    if let Some(len) = res.content_length() {
+        response.no_chunking(len);
+    }
+    // Chunks are written to the client as the service sends them, and only read from the
+    // service as fast as the client takes them
+    let name = service_to_forward.name.clone();
+    response.streaming(res.bytes_stream().map_err(move |e| {
+        log::error!("Failed to stream the response of {}: {}", name, e);
+        actix_web::error::ErrorBadGateway(e)
+    }))
+}
+
+// The actix payload can't leave the worker thread it arrived on, reqwest wants a body
+// it can send anywhere. A task on the worker pumps it through a small channel, which
+// only takes the next chunk once the service read the previous ones
+fn stream_payload(
+    mut payload: web::Payload,
+) -> futures::channel::mpsc::Receiver<std::io::Result<web::Bytes>> {
+    let (mut tx, rx) = futures::channel::mpsc::channel(PAYLOAD_CHUNKS_IN_FLIGHT);
+    rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| std::io::Error::other(e.to_string()));
+            // The service stopped reading, the request is over
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Sending an empty stream would turn a plain GET into a chunked request
+fn has_body(req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    headers.contains_key(header::TRANSFER_ENCODING)
+        || headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .is_some_and(|len| len != "0")
+}
+
+// Headers describing the connection to us rather than the request, the proxy's own
+// connections set their own
+fn is_hop_by_hop(name: &header::HeaderName) -> bool {
+    [
+        header::CONNECTION,
+        header::TRANSFER_ENCODING,
+        header::TE,
+        header::TRAILER,
+        header::UPGRADE,
+        header::PROXY_AUTHORIZATION,
+        header::PROXY_AUTHENTICATE,
+    ]
+    .contains(name)
+        || name.as_str() == "keep-alive"
+}
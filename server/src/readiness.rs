@@ -0,0 +1,205 @@
+use crate::prelude::*;
+use regex::Regex;
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Used when the lua file or the packet doesn't say otherwise
+pub const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Pause between two failed probes
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+// How long a single connection attempt may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub enum ReadinessProbe {
+    // Ready as soon as the process is spawned
+    None,
+    // Something accepts connections on the service port
+    Tcp,
+    // GET on the service port answers with the expected status
+    Http { path: String, status: u16 },
+    // A line of the service's stdout matches
+    Stdout(Regex),
+}
+
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    pub probe: ReadinessProbe,
+    pub timeout: Duration,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness {
+            probe: ReadinessProbe::None,
+            timeout: DEFAULT_READY_TIMEOUT,
+        }
+    }
+}
+
+// Blocks until the probe passes. Gives up when the timeout runs out or as soon as
+// `starting` reports the service is no longer starting (it exited or got detached)
+pub fn wait_until_ready(
+    name: &str,
+    readiness: &Readiness,
+    port: u16,
+    stdout_matched: &AtomicBool,
+    starting: impl Fn() -> bool,
+) -> Result<()> {
+    let deadline = Instant::now() + readiness.timeout;
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+    loop {
+        if !starting() {
+            return Err(Error::NotReady(
+                name.to_string(),
+                "it stopped while starting".to_string(),
+            ));
+        }
+
+        let probe = match &readiness.probe {
+            ReadinessProbe::None => Ok(()),
+            ReadinessProbe::Tcp => TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+                .map(|_| ())
+                .map_err(|e| f!("tcp connect to {}: {}", addr, e)),
+            ReadinessProbe::Http { path, status } => match http_status(&addr, path) {
+                Ok(got) if got == *status => Ok(()),
+                Ok(got) => Err(f!("GET {} answered {}, expected {}", path, got, status)),
+                Err(e) => Err(f!("GET {}: {}", path, e)),
+            },
+            ReadinessProbe::Stdout(regex) => match stdout_matched.load(Ordering::SeqCst) {
+                true => Ok(()),
+                false => Err(f!("no stdout line matched /{}/", regex)),
+            },
+        };
+
+        let failure = match probe {
+            Ok(()) => return Ok(()),
+            Err(failure) => failure,
+        };
+
+        if Instant::now() >= deadline {
+            return Err(Error::NotReady(
+                name.to_string(),
+                f!("not ready after {:?}, {}", readiness.timeout, failure),
+            ));
+        }
+        thread::sleep(PROBE_INTERVAL);
+    }
+}
+
+// Bare bones HTTP/1.1 request, only the status line of the answer is read. A path
+// without its leading slash ("health") gets one
+fn http_status(addr: &SocketAddr, path: &str) -> std::io::Result<u16> {
+    let mut stream = TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    let slash = if path.starts_with('/') { "" } else { "/" };
+    write!(
+        stream,
+        "GET {}{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        slash, path, addr
+    )?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                f!("bad status line {:?}", status_line.trim_end()),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn readiness(probe: ReadinessProbe, timeout: Duration) -> Readiness {
+        Readiness { probe, timeout }
+    }
+
+    #[test]
+    fn tcp_and_stdout_probes_pass() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tcp = readiness(ReadinessProbe::Tcp, Duration::from_secs(5));
+        assert!(wait_until_ready("api", &tcp, port, &AtomicBool::new(false), || true).is_ok());
+
+        let stdout = readiness(
+            ReadinessProbe::Stdout(Regex::new("listening").unwrap()),
+            Duration::from_secs(5),
+        );
+        assert!(wait_until_ready("api", &stdout, 0, &AtomicBool::new(true), || true).is_ok());
+    }
+
+    #[test]
+    fn http_probe_gets_the_path_with_a_slash() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream)
+                .read_line(&mut request_line)
+                .unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            request_line
+        });
+
+        let http = readiness(
+            ReadinessProbe::Http {
+                path: "health".to_string(),
+                status: 204,
+            },
+            Duration::from_secs(5),
+        );
+        assert!(wait_until_ready("api", &http, port, &AtomicBool::new(false), || true).is_ok());
+        assert_eq!(server.join().unwrap(), "GET /health HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn gives_up_when_the_timeout_runs_out() {
+        // Bound and dropped, nobody listens on it anymore
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let tcp = readiness(ReadinessProbe::Tcp, Duration::from_millis(300));
+        let started = Instant::now();
+        match wait_until_ready("api", &tcp, port, &AtomicBool::new(false), || true) {
+            Err(Error::NotReady(name, _)) => assert_eq!(name, "api"),
+            other => panic!("expected NotReady, got {:?}", other),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn gives_up_once_no_longer_starting() {
+        let stdout = readiness(
+            ReadinessProbe::Stdout(Regex::new("listening").unwrap()),
+            Duration::from_secs(60),
+        );
+        let probes = std::sync::atomic::AtomicUsize::new(0);
+        let started = Instant::now();
+        let result = wait_until_ready("api", &stdout, 0, &AtomicBool::new(false), || {
+            probes.fetch_add(1, Ordering::SeqCst) < 2
+        });
+        assert!(matches!(result, Err(Error::NotReady(..))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,26 +1,109 @@
+use crate::dependencies;
+use crate::log_files::LogFileConfig;
+use crate::ports;
 use crate::prelude::*;
+use crate::process_tree;
+use crate::proxy::RouteTable;
+use crate::readiness::{self, Readiness, ReadinessProbe};
+use crate::service_logs::{drain_output, LogBuffer, SharedLogs, MAX_LOG_LINES};
 use std::{
-    collections::HashMap,
-    io::Read,
+    collections::{HashMap, HashSet, VecDeque},
+    os::unix::process::{CommandExt, ExitStatusExt},
     path::PathBuf,
     process::Stdio,
-    process::{Child, Command},
-    sync::mpsc,
-    thread::{self, JoinHandle}, time::Duration,
+    process::{Child, Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use actix_web::{
-    dev::{Server, ServerHandle},
-    middleware, rt,
-    web::{self, Data},
-    App, HttpRequest, HttpResponse, HttpServer, Responder,
-};
+use actix_web::{dev::ServerHandle, rt};
 use log::{debug, info};
-use packet::Service;
-use reqwest::{header, Client};
+use packet::{
+    ExitKind, LogStream, ProbeKind, Response, RestartPolicy, Service, ServiceInfo, ServiceList,
+    ServiceState, StateInfo,
+};
+use regex::Regex;
 
 use uuid::Uuid;
 
+// Progress updates for long running commands, forwarded to the client as they happen
+pub type Progress = tokio::sync::mpsc::UnboundedSender<Response>;
+
+// How often the reaper polls attached children for an exit status
+const REAPER_INTERVAL: Duration = Duration::from_millis(500);
+
+// Number of state transitions kept per service
+const MAX_TRANSITIONS: usize = 16;
+
+// Services of a batch started at the same time, unless the lua file says otherwise
+pub const DEFAULT_MAX_PARALLEL: usize = 4;
+
+// How long killed processes get to disappear before we call them survivors
+const KILL_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Grace period between the stop signal and SIGKILL, unless the lua file says otherwise
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often a stopping child is checked on
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Upper bound for the supervisor's exponential backoff
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttachableState {
+    // Parsed, not spawned yet
+    Pending,
+    // Spawned, not accepting traffic yet
+    Starting,
+    Ready,
+    // Running, but we can't tell what it is doing
+    Unhealthy,
+    // Exited on its own
+    Exited(ExitStatus),
+    // Stopped by us
+    Stopped,
+}
+
+impl AttachableState {
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self,
+            AttachableState::Starting | AttachableState::Ready | AttachableState::Unhealthy
+        )
+    }
+
+    fn info(&self, since: SystemTime) -> StateInfo {
+        let (state, exit_kind, exit_value) = match self {
+            AttachableState::Pending => (ServiceState::Pending, ExitKind::None, 0),
+            AttachableState::Starting => (ServiceState::Starting, ExitKind::None, 0),
+            AttachableState::Ready => (ServiceState::Ready, ExitKind::None, 0),
+            AttachableState::Unhealthy => (ServiceState::Unhealthy, ExitKind::None, 0),
+            AttachableState::Exited(status) => match (status.code(), status.signal()) {
+                (Some(code), _) => (ServiceState::Exited, ExitKind::Code, code),
+                (None, Some(signal)) => (ServiceState::Exited, ExitKind::Signal, signal),
+                (None, None) => (ServiceState::Exited, ExitKind::None, 0),
+            },
+            AttachableState::Stopped => (ServiceState::Stopped, ExitKind::None, 0),
+        };
+        let since_unix_ms = since
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+
+        StateInfo {
+            state: state as u8,
+            exit_kind: exit_kind as u8,
+            exit_value,
+            since_unix_ms,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Attachable {
     pub id: String,
@@ -30,8 +113,62 @@ pub struct Attachable {
     pub path: PathBuf,
     pub attachable_type: u8,
     pub child_process: Option<Child>,
-    pub thread_handle: Option<JoinHandle<()>>,
+    // Readers draining the child's stdout and stderr
+    pub output_threads: Vec<JoinHandle<()>>,
+    // Recent output of the child, kept across restarts
+    pub logs: SharedLogs,
+    // Also write the output to rotating files
+    pub log_file: Option<LogFileConfig>,
+    // Set for the child on top of the server's environment, unless clear_env
+    pub env: Vec<(String, String)>,
+    pub clear_env: bool,
     pub port: u16,
+    // Variable the port is handed to the child in, always set for a port picked by us
+    pub port_env: Option<String>,
+    // Kill whatever holds the port instead of refusing to spawn
+    pub kill_port_holder: bool,
+    // Sent to the process group on stop, SIGKILL follows after stop_timeout
+    pub stop_signal: i32,
+    pub stop_timeout: Duration,
+    // How the last child ended, whether it exited on its own or was stopped
+    pub exit_status: Option<ExitStatus>,
+    // Process group and pids that outlived SIGKILL on the last stop, a retry kills them
+    // again
+    pub stop_survivors: Option<(u32, Vec<u32>)>,
+    pub started_at: Option<SystemTime>,
+    pub state: AttachableState,
+    // Oldest first, capped at MAX_TRANSITIONS
+    pub transitions: VecDeque<(AttachableState, SystemTime)>,
+    pub restart_policy: RestartConfig,
+    // When the supervisor restarted it, only the ones inside the restart window are kept
+    pub restarts: VecDeque<Instant>,
+    pub next_restart_at: Option<Instant>,
+    pub readiness: Readiness,
+    // Set by the stdout reader once a line matches ReadinessProbe::Stdout
+    pub stdout_matched: Arc<AtomicBool>,
+    // Names of the services that have to be ready before this one is spawned
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RestartConfig {
+    pub mode: RestartPolicy,
+    // Restarts allowed within `window` before the supervisor gives up
+    pub max_restarts: u16,
+    pub window: Duration,
+    // Backoff before the first restart, doubled for every following one
+    pub delay: Duration,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        RestartConfig {
+            mode: RestartPolicy::Never,
+            max_restarts: 5,
+            window: Duration::from_secs(60),
+            delay: Duration::from_secs(1),
+        }
+    }
 }
 
 impl Attachable {
@@ -51,9 +188,295 @@ impl Attachable {
             path,
             attachable_type,
             child_process: None,
-            thread_handle: None,
+            output_threads: vec![],
+            logs: Arc::new(Mutex::new(LogBuffer::new(MAX_LOG_LINES))),
+            log_file: None,
+            env: vec![],
+            clear_env: false,
+            // 0 asks for any free port
+            port_env: (port == 0).then(|| "PORT".to_string()),
+            kill_port_holder: false,
+            stop_signal: libc::SIGTERM,
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+            exit_status: None,
+            stop_survivors: None,
             port,
+            started_at: None,
+            state: AttachableState::Pending,
+            transitions: VecDeque::from([(AttachableState::Pending, SystemTime::now())]),
+            restart_policy: RestartConfig::default(),
+            restarts: VecDeque::new(),
+            next_restart_at: None,
+            readiness: Readiness::default(),
+            stdout_matched: Arc::new(AtomicBool::new(false)),
+            depends_on: vec![],
+        }
+    }
+
+    pub fn set_state(&mut self, state: AttachableState) {
+        if self.state == state {
+            return;
+        }
+        debug!("{}: {:?} -> {:?}", self.name, self.state, state);
+        self.state = state;
+        self.transitions.push_back((state, SystemTime::now()));
+        if self.transitions.len() > MAX_TRANSITIONS {
+            self.transitions.pop_front();
+        }
+    }
+
+    // Starts the process with the stored cmd, cmd_args and path, and a thread per
+    // output stream logging it
+    pub fn spawn(&mut self) -> Result<()> {
+        if self.port != 0 {
+            ports::claim(&self.name, self.port, self.kill_port_holder)?;
+        }
+        self.spawn_claimed()
+    }
+
+    // spawn, once the port is known to be free
+    fn spawn_claimed(&mut self) -> Result<()> {
+        if let Some(log_file) = &self.log_file {
+            self.logs.lock().unwrap().open_file(&self.name, log_file)?;
+        }
+
+        // Picked once, restarts keep the port so routes and clients don't have to move
+        if self.port == 0 {
+            self.port = ports::free_port().map_err(|e| Error::Spawn(self.name.clone(), e))?;
+            info!("{} gets port {}", self.name, self.port);
+        }
+
+        let mut command = Command::new(self.cmd.clone());
+        if self.clear_env {
+            command.env_clear();
+        }
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+        // After env, a PORT from an env file must not send the child elsewhere than the
+        // proxy and the readiness probe
+        if let Some(port_env) = &self.port_env {
+            command.env(port_env, self.port.to_string());
+        }
+        // Signals the server ignores (SIGINT when started in the background) would stay
+        // ignored in the child, and then a stop_signal could never reach it
+        unsafe {
+            command.pre_exec(|| {
+                for signal in [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM, libc::SIGHUP] {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+                Ok(())
+            });
+        }
+        let mut child = command
+            // Own process group, stopping the service reaches everything it started
+            .process_group(0)
+            .args(&self.cmd_args[..])
+            .current_dir(self.path.clone())
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Spawn(self.name.clone(), e))?;
+
+        // Save child handle
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        self.child_process = Some(child);
+        self.started_at = Some(SystemTime::now());
+        self.set_state(AttachableState::Starting);
+
+        let stdout_matched = Arc::new(AtomicBool::new(false));
+        self.stdout_matched = stdout_matched.clone();
+        let ready_regex = match &self.readiness.probe {
+            ReadinessProbe::Stdout(regex) => Some(regex.clone()),
+            _ => None,
+        };
+        self.output_threads = vec![
+            drain_output(
+                &self.name,
+                LogStream::Stdout,
+                stdout,
+                self.logs.clone(),
+                move |line| {
+                    if let Some(regex) = &ready_regex {
+                        if regex.is_match(line) {
+                            stdout_matched.store(true, Ordering::SeqCst);
+                        }
+                    }
+                },
+            ),
+            drain_output(
+                &self.name,
+                LogStream::Stderr,
+                stderr,
+                self.logs.clone(),
+                |_| {},
+            ),
+        ];
+        Ok(())
+    }
+
+    // Sends stop_signal to the child and everything it started, and kills whatever is
+    // still around after stop_timeout. Returns the exit status of the child, None if
+    // there was none
+    pub fn stop(&mut self) -> Result<Option<ExitStatus>> {
+        let Some(mut child) = self.child_process.take() else {
+            // The child is gone already, only what outlived the last stop is left
+            let Some((pgid, survivors)) = self.stop_survivors.take() else {
+                return Ok(None);
+            };
+            process_tree::signal_tree(pgid, &survivors, libc::SIGKILL);
+            self.wait_gone(pgid, &survivors)?;
+            return Ok(self.exit_status);
+        };
+        let pid = child.id();
+        // Exited already, collected by the reaper (which killed what it left behind) or
+        // just now. Its pid may belong to another process by now, it gets no signals
+        if let Some(status) = child.try_wait()? {
+            if self.state.is_running() {
+                let _ = process_tree::signal_group(pid, libc::SIGKILL);
+            }
+            self.exit_status = Some(status);
+            return Ok(Some(status));
+        }
+        let tree = process_tree::descendants(pid);
+
+        debug!(
+            "Sending {} to {}",
+            process_tree::signal_name(self.stop_signal),
+            self.name
+        );
+        process_tree::signal_tree(pid, &tree, self.stop_signal);
+        let deadline = Instant::now() + self.stop_timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if Instant::now() >= deadline {
+                break None;
+            }
+            thread::sleep(STOP_POLL_INTERVAL);
+        };
+        // The rest of the tree gets whatever is left of the grace period
+        let left = match status {
+            Some(_) => process_tree::wait_gone(
+                pid,
+                &tree,
+                deadline.saturating_duration_since(Instant::now()),
+            ),
+            None => vec![],
+        };
+
+        if status.is_none() || !left.is_empty() {
+            log::warn!(
+                "{} did not stop within {:?}, killing it",
+                self.name,
+                self.stop_timeout
+            );
+            process_tree::signal_tree(pid, &[&tree[..], &left[..]].concat(), libc::SIGKILL);
         }
+        let status = match status {
+            Some(status) => status,
+            None => child.wait()?,
+        };
+        self.exit_status = Some(status);
+
+        self.wait_gone(pid, &tree)?;
+        Ok(Some(status))
+    }
+
+    // Fails when anything of the group or `pids` is still around after KILL_TIMEOUT,
+    // remembering it for the next stop
+    fn wait_gone(&mut self, pgid: u32, pids: &[u32]) -> Result<()> {
+        let survivors = process_tree::wait_gone(pgid, pids, KILL_TIMEOUT);
+        if survivors.is_empty() {
+            return Ok(());
+        }
+        let e = Error::StopFailed(
+            self.name.clone(),
+            f!("pids {:?} survived SIGKILL", survivors),
+        );
+        self.stop_survivors = Some((pgid, survivors));
+        Err(e)
+    }
+
+    // Decides whether the supervisor should bring an exited service back, and when.
+    // Returns the backoff delay, doubling with every restart inside the window
+    fn schedule_restart(&mut self, status: ExitStatus) -> Option<Duration> {
+        let policy = &self.restart_policy;
+        let wanted = match policy.mode {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !status.success(),
+            RestartPolicy::Always => true,
+        };
+        if !wanted {
+            return None;
+        }
+
+        let now = Instant::now();
+        while let Some(restarted_at) = self.restarts.front() {
+            if now.duration_since(*restarted_at) > policy.window {
+                self.restarts.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.restarts.len() >= policy.max_restarts as usize {
+            log::error!(
+                "{} restarted {} times within {:?}, giving up",
+                self.name,
+                self.restarts.len(),
+                policy.window
+            );
+            return None;
+        }
+
+        let delay = policy
+            .delay
+            .saturating_mul(1 << self.restarts.len().min(16))
+            .min(MAX_RESTART_DELAY);
+        self.next_restart_at = Some(now + delay);
+        Some(delay)
+    }
+
+    // Snapshot sent to clients asking for the service inventory
+    pub fn info(&self) -> Result<ServiceInfo> {
+        let command = std::iter::once(&self.cmd)
+            .chain(self.cmd_args.iter())
+            .cloned()
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let pid = match (&self.child_process, self.state.is_running()) {
+            (Some(child), true) => child.id(),
+            _ => 0,
+        };
+        let uptime_secs = match self.state.is_running() {
+            true => self
+                .started_at
+                .and_then(|started_at| started_at.elapsed().ok())
+                .map(|uptime| uptime.as_secs())
+                .unwrap_or(0),
+            false => 0,
+        };
+        let transitions: Vec<StateInfo> = self
+            .transitions
+            .iter()
+            .map(|(state, since)| state.info(*since))
+            .collect();
+        let state = transitions.last().unwrap().clone();
+
+        let mut info = ServiceInfo::new(
+            &self.id,
+            &self.name,
+            self.attachable_type,
+            self.port,
+            &self.path.to_string_lossy(),
+            &command,
+            state,
+        )?
+        .with_transitions(transitions)?;
+        info.pid = pid;
+        info.uptime_secs = uptime_secs;
+        Ok(info)
     }
 }
 
@@ -84,216 +507,704 @@ impl TryFrom<Service> for Attachable {
 
     fn try_from(service: Service) -> Result<Self> {
         let svc_name = std::str::from_utf8(&service.svc_name)
-            .map_err(|e| Error::Generic(e.to_string()))
-            .unwrap();
+            .map_err(|e| Error::InvalidService(e.to_string()))?;
         let svc_path = std::str::from_utf8(&service.svc_path)
-            .map_err(|e| Error::Generic(e.to_string()))
-            .unwrap();
-        let shell_cmd = std::str::from_utf8(&service.cmd)
-            .map_err(|e| Error::Generic(e.to_string()))
-            .unwrap();
+            .map_err(|e| Error::InvalidService(e.to_string()))?;
+        let shell_cmd =
+            std::str::from_utf8(&service.cmd).map_err(|e| Error::InvalidService(e.to_string()))?;
         let cmd_args: Vec<String> = std::str::from_utf8(&service.cmd_args)
-            .map_err(|e| Error::Generic(e.to_string()))
-            .unwrap()
-            .split(",")
-            .collect::<Vec<&str>>()
-            .iter()
+            .map_err(|e| Error::InvalidService(e.to_string()))?
+            .split(',')
             .map(|arg| arg.to_string())
             .collect();
 
-        Ok(Attachable {
-            id: Uuid::new_v4().to_string(),
-            name: svc_name.to_string(),
-            path: PathBuf::from(svc_path),
-            cmd: shell_cmd.to_string(),
+        let mut attachable = Attachable::new(
+            svc_name.to_string(),
+            shell_cmd.to_string(),
             cmd_args,
-            attachable_type: service.svc_type,
-            thread_handle: None,
-            child_process: None,
-            port: service.svc_port,
-        })
+            PathBuf::from(svc_path),
+            service.svc_type,
+            service.svc_port,
+        );
+        attachable.restart_policy = RestartConfig {
+            mode: RestartPolicy::try_from(service.restart_policy)
+                .map_err(|e| Error::InvalidService(e.to_string()))?,
+            max_restarts: service.max_restarts,
+            window: Duration::from_secs(service.restart_window_secs as u64),
+            delay: Duration::from_millis(service.restart_delay_ms as u64),
+        };
+        for var in service.env.iter() {
+            let key = String::from_utf8(var.key.clone())
+                .map_err(|e| Error::InvalidService(e.to_string()))?;
+            let value = String::from_utf8(var.value.clone())
+                .map_err(|e| Error::InvalidService(e.to_string()))?;
+            attachable.env.push((key, value));
+        }
+        attachable.clear_env = service.clear_env != 0;
+
+        let ready_target = std::str::from_utf8(&service.ready_target)
+            .map_err(|e| Error::InvalidService(e.to_string()))?;
+        let probe = match ProbeKind::try_from(service.ready_probe)
+            .map_err(|e| Error::InvalidService(e.to_string()))?
+        {
+            ProbeKind::None => ReadinessProbe::None,
+            ProbeKind::Tcp => ReadinessProbe::Tcp,
+            ProbeKind::Http => ReadinessProbe::Http {
+                path: ready_target.to_string(),
+                status: service.ready_status,
+            },
+            ProbeKind::Stdout => ReadinessProbe::Stdout(
+                Regex::new(ready_target).map_err(|e| Error::InvalidService(e.to_string()))?,
+            ),
+        };
+        attachable.readiness = Readiness {
+            probe,
+            timeout: Duration::from_millis(service.ready_timeout_ms as u64),
+        };
+        Ok(attachable)
+    }
+}
+
+// What happened to a service of a batch
+#[derive(Debug)]
+pub enum AttachOutcome {
+    Ready,
+    Failed(Error),
+    // Never spawned because the named dependency didn't make it
+    Skipped(String),
+}
+
+impl std::fmt::Display for AttachOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AttachOutcome::Ready => write!(f, "ready"),
+            AttachOutcome::Failed(e) => write!(f, "failed, {}", e),
+            AttachOutcome::Skipped(dependency) => write!(f, "skipped, {} didn't start", dependency),
+        }
+    }
+}
+
+fn report(progress: &Progress, done: usize, total: usize, name: &str, outcome: &AttachOutcome) {
+    if let Ok(update) = Response::partial(done as u32, total as u32, &f!("{}: {}", name, outcome)) {
+        let _ = progress.send(update);
     }
 }
 
 pub struct ServiceAttacher {
     pub services: HashMap<String, Attachable>,
+    pub routes: RouteTable,
+    // The proxy, started once by main
     pub http_server_handle: Option<ServerHandle>,
+    // Set once shutdown started, nothing gets attached or routed to anymore
+    pub shutting_down: bool,
 }
 
 impl ServiceAttacher {
-    pub(crate) fn attach(&mut self, mut attachable: Attachable) {
-        let mut child = Command::new(attachable.cmd.clone())
-            .args(&attachable.cmd_args[..])
-            .current_dir(attachable.path.clone())
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .expect("Failed to spawn process");
+    // Spawns the service and waits for its readiness probe before routing to it
+    //
+    // Takes the lock rather than &mut self so it is only held while the child is being
+    // spawned, the control server keeps answering other clients in the meantime
+    pub fn attach(
+        service_attacher: &RwLock<ServiceAttacher>,
+        mut attachable: Attachable,
+    ) -> Result<()> {
+        let name = attachable.name.clone();
+        if service_attacher
+            .read()
+            .unwrap()
+            .services
+            .contains_key(&name)
+        {
+            return Err(already_attached(&name));
+        }
+        attachable.spawn()?;
+        // Save attachable
+        {
+            let mut service_attacher = service_attacher.write().unwrap();
+            if service_attacher.shutting_down {
+                // Spawned while shutdown was already stopping the others
+                drop(service_attacher);
+                attachable.stop()?;
+                return Err(Error::ShuttingDown);
+            }
+            if service_attacher.services.contains_key(&name) {
+                // Attached by someone else while this one was spawning
+                drop(service_attacher);
+                attachable.stop()?;
+                return Err(already_attached(&name));
+            }
+            service_attacher.services.insert(name.clone(), attachable);
+        }
 
-        // Save child handle
-        let mut stdout = child.stdout.take().unwrap();
-        attachable.child_process = Some(child);
+        Self::await_ready(service_attacher, &name)
+    }
 
-        let thread_handle = thread::spawn(move || {
-            let mut stdout_buf = [0; 4096];
+    // Attaches an array of services, each one as soon as its dependencies are ready and
+    // at most `max_parallel` at a time. Failures don't stop the batch, only the services
+    // depending on the failed one are skipped
+    //
+    // Outcomes are returned in startup order
+    // Useful when attaching a service list parsed from the lua services file
+    pub fn batch_attach(
+        service_attacher: &RwLock<ServiceAttacher>,
+        attachables: Vec<Attachable>,
+        max_parallel: usize,
+        progress: &Progress,
+    ) -> Result<Vec<(String, AttachOutcome)>> {
+        let attached: HashSet<String> = service_attacher
+            .read()
+            .unwrap()
+            .services
+            .keys()
+            .cloned()
+            .collect();
+        let attachables = dependencies::startup_order(attachables, &attached)?;
+        let names: Vec<String> = attachables.iter().map(|a| a.name.clone()).collect();
+
+        let service_count = attachables.len();
+        debug!(
+            "Begin attaching {} services, {} at a time",
+            service_count, max_parallel
+        );
+        let mut pending: Vec<Option<Attachable>> = attachables.into_iter().map(Some).collect();
+        let mut outcomes: HashMap<String, AttachOutcome> = HashMap::new();
+        let (done_tx, done_rx) = mpsc::channel::<(String, Result<()>)>();
 
+        thread::scope(|scope| {
+            let mut running = 0;
             loop {
-                match stdout.read(&mut stdout_buf) {
-                    Ok(n) if n == 0 => break,
-                    Ok(n) => {
-                        let output = std::str::from_utf8(&stdout_buf[..n]).unwrap();
-                        debug!("service: {}", output);
+                for slot in pending.iter_mut() {
+                    let Some(attachable) = slot else { continue };
+                    // Waiting on a dependency of the batch that hasn't finished yet
+                    if attachable
+                        .depends_on
+                        .iter()
+                        .any(|d| names.contains(d) && !outcomes.contains_key(d))
+                    {
+                        continue;
                     }
-                    Err(e) => {
-                        log::error!("Error reading stdout: {}", e);
+
+                    let failed_dependency = attachable.depends_on.iter().find(|d| {
+                        outcomes
+                            .get(d.as_str())
+                            .is_some_and(|outcome| !matches!(outcome, AttachOutcome::Ready))
+                    });
+                    if let Some(dependency) = failed_dependency {
+                        let outcome = AttachOutcome::Skipped(dependency.clone());
+                        let name = attachable.name.clone();
+                        report(progress, outcomes.len() + 1, service_count, &name, &outcome);
+                        outcomes.insert(name, outcome);
+                        *slot = None;
+                        continue;
+                    }
+
+                    if running >= max_parallel {
                         break;
                     }
+                    let attachable = slot.take().unwrap();
+                    debug!(
+                        "Attaching {} on port {}",
+                        &attachable.name, &attachable.port
+                    );
+                    let done_tx = done_tx.clone();
+                    running += 1;
+                    scope.spawn(move || {
+                        let name = attachable.name.clone();
+                        let checked = service_attacher
+                            .read()
+                            .unwrap()
+                            .check_dependencies(&attachable);
+                        let result =
+                            checked.and_then(|_| Self::attach(service_attacher, attachable));
+                        let _ = done_tx.send((name, result));
+                    });
                 }
+
+                if running == 0 {
+                    break;
+                }
+                let (name, result) = done_rx.recv().unwrap();
+                running -= 1;
+                let outcome = match result {
+                    Ok(()) => AttachOutcome::Ready,
+                    Err(e) => AttachOutcome::Failed(e),
+                };
+                report(progress, outcomes.len() + 1, service_count, &name, &outcome);
+                outcomes.insert(name, outcome);
             }
         });
-        attachable.thread_handle = Some(thread_handle);
-        // Save attachable
-        self.services.insert(attachable.name.clone(), attachable);
-        self.attach_http_services();
+
+        debug!("Done attaching {} services", service_count);
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let outcome = outcomes.remove(&name).unwrap();
+                (name, outcome)
+            })
+            .collect())
     }
-    
-    pub fn batch_attach(&mut self, attachables: Vec<Attachable>) {
-        // Attaches an array of services
-        // Useful when attaching a service list parsed from the lua services file
-        let service_count = attachables.len();
-        debug!("Begin attaching {} services", service_count);
-        for mut attachable in attachables {
-            // Good idea to parallelize here?
-            debug!("Attaching {} on port {}", &attachable.name, &attachable.port);
-            let child = Command::new(attachable.cmd.clone())
-                .args(&attachable.cmd_args[..])
-                .current_dir(attachable.path.clone())
-                .stderr(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("Failed to spawn process");
-
-            // Save child handle
-            attachable.child_process = Some(child);
-
-            // Save attachable
-            self.services.insert(attachable.name.clone(), attachable);
-            thread::sleep(Duration::from_secs(15)); // Add some breathing time between each
-                                                    // service... TODO: is there a way we can wait
-                                                    // for the child to finish setting up?
-        }
-        debug!("Attached {} services succesfully", service_count);
-        self.attach_http_services();
+
+    // Runs the readiness probe of a freshly spawned service without holding the lock,
+    // then marks it Ready and routes to it, or Unhealthy if the probe gave up
+    pub fn await_ready(service_attacher: &RwLock<ServiceAttacher>, name: &str) -> Result<()> {
+        let (readiness, port, stdout_matched) = {
+            let service_attacher = service_attacher.read().unwrap();
+            let attachable = service_attacher
+                .services
+                .get(name)
+                .ok_or_else(|| Error::UnknownService(name.to_string()))?;
+            (
+                attachable.readiness.clone(),
+                attachable.port,
+                attachable.stdout_matched.clone(),
+            )
+        };
+
+        let starting = || {
+            service_attacher
+                .read()
+                .unwrap()
+                .services
+                .get(name)
+                .is_some_and(|attachable| attachable.state == AttachableState::Starting)
+        };
+        let result = readiness::wait_until_ready(name, &readiness, port, &stdout_matched, starting);
+
+        let mut service_attacher = service_attacher.write().unwrap();
+        let attachable = service_attacher
+            .services
+            .get_mut(name)
+            .ok_or_else(|| Error::UnknownService(name.to_string()))?;
+        if attachable.state != AttachableState::Starting {
+            // Exited or got detached while we were probing
+            return result;
+        }
+
+        match result {
+            Ok(()) => {
+                attachable.set_state(AttachableState::Ready);
+                info!("{} is ready", name);
+                if attachable.attachable_type == 1 {
+                    service_attacher.update_routes();
+                }
+                Ok(())
+            }
+            Err(e) => {
+                attachable.set_state(AttachableState::Unhealthy);
+                Err(e)
+            }
+        }
+    }
+
+    // Dependencies in the same batch were awaited when they were attached, but the ones
+    // that were already running may have died or never become ready
+    fn check_dependencies(&self, attachable: &Attachable) -> Result<()> {
+        for dependency in attachable.depends_on.iter() {
+            match self.services.get(dependency).map(|d| d.state) {
+                Some(AttachableState::Ready) => {}
+                Some(state) => {
+                    return Err(Error::NotReady(
+                        attachable.name.clone(),
+                        f!("dependency {} is {:?}", dependency, state),
+                    ))
+                }
+                None => {
+                    return Err(Error::NotReady(
+                        attachable.name.clone(),
+                        f!("dependency {} is not attached", dependency),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<ServiceList> {
+        let mut services: Vec<ServiceInfo> = self
+            .services
+            .values()
+            .map(|service| service.info())
+            .collect::<Result<Vec<ServiceInfo>>>()?;
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(ServiceList {
+            count: services.len() as u32,
+            services,
+        })
+    }
+
+    // Looks an attached service up by its name or its id
+    pub fn find(&self, name_or_id: &str) -> Result<&Attachable> {
+        self.services
+            .values()
+            .find(|service| service.name == name_or_id || service.id == name_or_id)
+            .ok_or_else(|| Error::UnknownService(name_or_id.to_string()))
+    }
+
+    // The service stops receiving traffic right away, the stop itself happens without
+    // holding the lock since it can take up to the service's stop_timeout
+    pub(crate) fn detach(
+        service_attacher: &RwLock<ServiceAttacher>,
+        name_or_id: &str,
+    ) -> Result<Attachable> {
+        let mut attachable = {
+            let mut service_attacher = service_attacher.write().unwrap();
+            let name = service_attacher.find(name_or_id)?.name.clone();
+            let attachable = service_attacher.services.remove(&name).unwrap();
+            service_attacher.update_routes();
+            attachable
+        };
+        debug!("Detaching {} ({})", attachable.name, attachable.id);
+
+        let stopped = attachable.stop();
+        attachable.set_state(AttachableState::Stopped);
+        if let Err(e) = stopped {
+            // Survivors may still hold the pipes, don't wait for the output readers then.
+            // Kept attached so status shows what happened, and a detach can be retried,
+            // killing the survivors again
+            service_attacher
+                .write()
+                .unwrap()
+                .services
+                .entry(attachable.name.clone())
+                .or_insert(attachable);
+            return Err(e);
+        }
+        for output_thread in attachable.output_threads.drain(..) {
+            if output_thread.join().is_err() {
+                log::error!("output reader for {} panicked", attachable.name);
+            }
+        }
+
+        info!("Detached {}", attachable.name);
+        Ok(attachable)
+    }
+
+    // Drains the http proxy, then stops every service, dependents before the services
+    // they depend on. Returns how each of them ended, in the order they were stopped
+    pub fn shutdown(
+        service_attacher: &RwLock<ServiceAttacher>,
+    ) -> Vec<(String, Result<Option<ExitStatus>>)> {
+        let mut attachables: Vec<Attachable> = {
+            let mut service_attacher = service_attacher.write().unwrap();
+            service_attacher.shutting_down = true;
+            if let Some(handle) = service_attacher.http_server_handle.take() {
+                info!("Draining the http proxy");
+                rt::System::new().block_on(handle.stop(true));
+            }
+
+            let attached: Vec<&Attachable> = service_attacher.services.values().collect();
+            // Out of the map, the reaper must not restart them while they are stopped
+            dependencies::shutdown_order(&attached)
+                .into_iter()
+                .map(|name| service_attacher.services.remove(&name).unwrap())
+                .collect()
+        };
+
+        attachables
+            .iter_mut()
+            .map(|attachable| {
+                info!("Stopping {}", attachable.name);
+                let stopped = attachable.stop();
+                attachable.set_state(AttachableState::Stopped);
+                (attachable.name.clone(), stopped)
+            })
+            .collect()
     }
 
-    fn attach_http_services(&mut self) {
-        // If we already have a http server open, let's shut it down
-        if let Some(handle) = &self.http_server_handle {
-            info!("Gracefully shutting down http server");
-            rt::System::new().block_on(handle.stop(true));
+    // Records the exit of any child that stopped on its own, dropping its route.
+    // Returns the services whose restart policy asks for a restart and whose backoff is
+    // over, see restart
+    fn reap(&mut self) -> Vec<String> {
+        let mut routes_changed = false;
+        let mut due = vec![];
+        for service in self.services.values_mut() {
+            if service
+                .next_restart_at
+                .is_some_and(|restart_at| restart_at <= Instant::now())
+            {
+                service.next_restart_at = None;
+                service.restarts.push_back(Instant::now());
+                due.push(service.name.clone());
+                continue;
+            }
+
+            if !service.state.is_running() {
+                continue;
+            }
+            let child = match service.child_process.as_mut() {
+                Some(child) => child,
+                None => continue,
+            };
+
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    log::warn!("{} exited: {}", service.name, status);
+                    // Whatever it started goes with it, a restart must not find an orphan
+                    // sitting on its port
+                    let pgid = child.id();
+                    let leftovers = process_tree::survivors(pgid, &[]);
+                    if !leftovers.is_empty() {
+                        log::warn!(
+                            "Killing {} leftover processes of {}",
+                            leftovers.len(),
+                            service.name
+                        );
+                        let _ = process_tree::signal_group(pgid, libc::SIGKILL);
+                    }
+                    routes_changed |= service.state == AttachableState::Ready;
+                    service.exit_status = Some(status);
+                    service.set_state(AttachableState::Exited(status));
+                    if let Some(delay) = service.schedule_restart(status) {
+                        info!("{} will be restarted in {:?}", service.name, delay);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("Failed to poll {}: {}", service.name, e);
+                    service.set_state(AttachableState::Unhealthy);
+                }
+            }
+        }
+
+        if routes_changed {
+            self.update_routes();
+        }
+        due
+    }
+
+    // Respawns a service reap found due, telling whether it was. Claiming the port can
+    // mean killing its holder and waiting for it, the lock is only taken around it so
+    // queries don't wait as well. Readiness still has to be awaited
+    fn restart(service_attacher: &RwLock<ServiceAttacher>, name: &str) -> bool {
+        let (port, kill_port_holder) = match service_attacher.read().unwrap().services.get(name) {
+            Some(service) => (service.port, service.kill_port_holder),
+            None => return false,
+        };
+        let claimed = match port {
+            0 => Ok(()),
+            port => ports::claim(name, port, kill_port_holder),
         };
 
+        let mut service_attacher = service_attacher.write().unwrap();
+        // Detached, or detached and attached again, in the meantime
+        let Some(service) = service_attacher.services.get_mut(name) else {
+            return false;
+        };
+        let AttachableState::Exited(status) = service.state else {
+            return false;
+        };
+        info!("Restarting {}", name);
+        match claimed.and_then(|()| service.spawn_claimed()) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Supervisor failed to restart: {}", e);
+                // Counted as a restart, the backoff and the restart window decide whether
+                // there is another attempt
+                if let Some(delay) = service.schedule_restart(status) {
+                    info!("{} will be retried in {:?}", name, delay);
+                }
+                false
+            }
+        }
+    }
+
+    // Hands the proxy a fresh route table, requests in flight finish on the old one
+    fn update_routes(&mut self) {
         let http_service_map: HashMap<String, HttpAttachable> =
             self.services.iter().fold(HashMap::new(), |mut acc, value| {
                 let (_, service) = value;
-                if service.attachable_type == 1 {
-                    acc.insert(
-                        service.name.clone(),
-                        HttpAttachable::try_from(service).unwrap(),
-                    );
+                // Only route to services that can take traffic
+                if service.attachable_type == 1 && service.state == AttachableState::Ready {
+                    let http_attachable = HttpAttachable::try_from(service).unwrap();
+                    acc.insert(http_attachable.route_path.clone(), http_attachable);
                 }
-                return acc;
+                acc
             });
-
-        let (tx, rx) = mpsc::channel();
-        log::debug!("spawning thread for server");
-        thread::spawn(move || {
-            let server_future = run_http_server(tx, http_service_map.clone());
-            rt::System::new().block_on(server_future)
-        });
-
-        self.http_server_handle = Some(rx.recv().unwrap());
+        self.routes.swap(http_service_map);
     }
 }
 
-// Should be moved to separate file after
-// Run actix in a thread
-async fn forward_request(
-    client: web::Data<Client>,
-    req: HttpRequest,
-    http_services: web::Data<HashMap<String, HttpAttachable>>,
-    body: web::Bytes,
-) -> impl Responder {
-    info!("Received a new request, attempting to find a service to route it to");
-    let path_no_leading = req.path().chars().skip(1).collect::<String>(); // Remove the leading / from
-                                                                          // the path
-    let path_parts: Vec<&str> = path_no_leading.split("/").collect();
-
-    debug!("path_stem: {}, path_parts: {:?}", path_parts[0], path_parts);
-    let path_to_forward = if path_parts.len() > 1 {
-        f!("{}", path_parts[1..].join("/"))
-    } else {
-        "".to_string()
-    };
-    debug!("path_to_forward: {}", path_to_forward);
-
-    let service_to_forward = http_services.get(path_parts[0]).unwrap();
-
-    // Construct request URL
-    let request_url = f!(
-        "http://localhost:{}/{}",
-        service_to_forward.port,
-        path_to_forward
-    );
-
-    let actix_headers = req.headers().clone();
-    let mut reqwest_headers = header::HeaderMap::new();
-
-    actix_headers.iter().for_each(|value| {
-        let (header_name, header_value) = value.clone();
-        reqwest_headers.insert(header_name.clone(), header_value.clone());
-    });
-
-    let res = client
-        .request(req.method().clone(), request_url)
-        .headers(reqwest_headers)
-        .body(body.to_vec())
-        .send()
-        .await
-        .unwrap();
-
-    HttpResponse::build(res.status().into()).body(res.text().await.unwrap())
+// Replacing an attached service would leave its child running where nothing reaches it
+pub(crate) fn already_attached(name: &str) -> Error {
+    Error::InvalidService(f!("{} is already attached, detach it first", name))
 }
 
-async fn run_http_server(
-    tx: mpsc::Sender<ServerHandle>,
-    http_services: HashMap<String, HttpAttachable>,
-) -> Result<()> {
-    info!("starting HTTP server at localhost:9000");
-    let server = HttpServer::new(move || {
-        let http_client = Client::new();
-        
-        http_services.iter().for_each(|item| {
-            let (_, value) = item;
-            info!("requests to http://localhost:9000/{} are now being routed to http://localhost:{}", value.name, value.port);
+// Polls every attached child in the background so crashed services stop looking
+// attached and stop receiving traffic
+pub fn spawn_reaper(service_attacher: &'static RwLock<ServiceAttacher>) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(REAPER_INTERVAL);
+        let due = service_attacher.write().unwrap().reap();
+        for name in due {
+            if !ServiceAttacher::restart(service_attacher, &name) {
+                continue;
+            }
+            thread::spawn(move || {
+                if let Err(e) = ServiceAttacher::await_ready(service_attacher, &name) {
+                    log::error!("{}", e);
+                }
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_respawns_are_retried() {
+        let mut attachable = Attachable::new(
+            "flaky".to_string(),
+            "/nonexistent/flaky".to_string(),
+            vec![],
+            PathBuf::from("/"),
+            0,
+            0,
+        );
+        attachable.restart_policy = RestartConfig {
+            mode: RestartPolicy::OnFailure,
+            max_restarts: 3,
+            window: Duration::from_secs(60),
+            delay: Duration::ZERO,
+        };
+        attachable.set_state(AttachableState::Exited(ExitStatus::from_raw(1 << 8)));
+        attachable.next_restart_at = Some(Instant::now());
+
+        let service_attacher = RwLock::new(ServiceAttacher {
+            services: HashMap::from([("flaky".to_string(), attachable)]),
+            routes: RouteTable::default(),
+            http_server_handle: None,
+            shutting_down: false,
         });
+        for attempt in 1..=3 {
+            assert_eq!(service_attacher.write().unwrap().reap(), ["flaky"]);
+            assert!(!ServiceAttacher::restart(&service_attacher, "flaky"));
+            let service_attacher = service_attacher.read().unwrap();
+            let flaky = &service_attacher.services["flaky"];
+            assert_eq!(flaky.restarts.len(), attempt);
+            // Retried until max_restarts is used up
+            assert_eq!(flaky.next_restart_at.is_some(), attempt < 3);
+        }
+    }
 
-        let app = App::new()
-            .app_data(Data::new(http_client.clone()))
-            .app_data(Data::new(http_services.clone()))
-            .route("/{tail}*", web::patch().to(forward_request))
-            .route("/{tail}*", web::put().to(forward_request))
-            .route("/{tail}*", web::delete().to(forward_request))
-            .route("/{tail}*", web::get().to(forward_request))
-            .route("/{tail}*", web::post().to(forward_request));
-        return app;
-    })
-    .bind("127.0.0.1:9000")
-        .unwrap()
-        .workers(2)
-        .run();
+    #[test]
+    fn stopping_a_reaped_service_sends_no_signals() {
+        let mut attachable = Attachable::new(
+            "crashed".to_string(),
+            "sh".to_string(),
+            vec!["-c".to_string(), "exit 3".to_string()],
+            PathBuf::from("/"),
+            0,
+            0,
+        );
+        attachable.spawn().unwrap();
+        let mut service_attacher = ServiceAttacher {
+            services: HashMap::from([("crashed".to_string(), attachable)]),
+            routes: RouteTable::default(),
+            http_server_handle: None,
+            shutting_down: false,
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while service_attacher.services["crashed"].state.is_running() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(20));
+            service_attacher.reap();
+        }
+
+        let mut crashed = service_attacher.services.remove("crashed").unwrap();
+        // Stopping a live child would take stop_timeout at least
+        crashed.stop_timeout = Duration::from_secs(60);
+        let started = Instant::now();
+        let status = crashed.stop().unwrap().unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
 
+    #[test]
+    fn stop_signal_ignored_escalates_to_sigkill() {
+        let mut attachable = Attachable::new(
+            "stubborn".to_string(),
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                "trap '' TERM; echo trapped; sleep 60".to_string(),
+            ],
+            PathBuf::from("/"),
+            0,
+            0,
+        );
+        attachable.stop_timeout = Duration::from_millis(300);
+        attachable.spawn().unwrap();
+        // Signals before the trap is set would still kill it
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while attachable
+            .logs
+            .lock()
+            .unwrap()
+            .query(0, None, None)
+            .is_empty()
+        {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(20));
+        }
+        let pgid = attachable.child_process.as_ref().unwrap().id();
+
+        let status = attachable.stop().unwrap().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert_eq!(attachable.exit_status, Some(status));
+        assert!(process_tree::survivors(pgid, &[]).is_empty());
+    }
+
+    #[test]
+    fn stopping_again_kills_the_survivors() {
+        let mut survivor = Command::new("sleep")
+            .arg("60")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pgid = survivor.id();
+        let mut attachable = Attachable::new(
+            "stuck".to_string(),
+            "true".to_string(),
+            vec![],
+            PathBuf::from("/"),
+            0,
+            0,
+        );
+        attachable.exit_status = Some(ExitStatus::from_raw(libc::SIGKILL));
+        attachable.stop_survivors = Some((pgid, vec![pgid]));
 
-    let _ = tx.send(server.handle());
-    server.await.map_err(|e| Error::Generic(e.to_string()))
+        assert_eq!(attachable.stop().unwrap(), attachable.exit_status);
+        assert!(attachable.stop_survivors.is_none());
+        assert_eq!(survivor.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+
+    #[test]
+    fn picked_port_wins_over_env() {
+        let mut attachable = Attachable::new(
+            "auto".to_string(),
+            "sh".to_string(),
+            vec!["-c".to_string(), "echo $PORT".to_string()],
+            PathBuf::from("/"),
+            0,
+            0,
+        );
+        attachable.env = vec![("PORT".to_string(), "3000".to_string())];
+        attachable.spawn().unwrap();
+        attachable.child_process.as_mut().unwrap().wait().unwrap();
+        for output_thread in attachable.output_threads.drain(..) {
+            output_thread.join().unwrap();
+        }
+
+        let logs = attachable.logs.lock().unwrap();
+        let lines: Vec<&str> = logs
+            .query(0, None, None)
+            .iter()
+            .map(|entry| entry.line.as_str())
+            .collect();
+        assert_ne!(attachable.port, 3000);
+        assert_eq!(lines, [attachable.port.to_string()]);
+    }
 }
@@ -0,0 +1,302 @@
+use crate::log_files::{format_utc, LogFileConfig, RotatingFile};
+use crate::prelude::*;
+use lazy_static::lazy_static;
+use log::info;
+use packet::{LogLine, LogStream};
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{self, BufRead, BufReader, Read},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+
+// Lines kept in memory per service, older ones are dropped first
+pub const MAX_LOG_LINES: usize = 1000;
+
+// Lines a follower can fall behind by before it starts missing some
+const FOLLOW_BUFFER: usize = 1024;
+
+// Longer output is split into lines of this size, progress bars and binary output may
+// never print a newline
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+lazy_static! {
+    // Every line of every service, for the clients following logs
+    static ref LOG_EVENTS: broadcast::Sender<(Arc<str>, LogEntry)> =
+        broadcast::channel(FOLLOW_BUFFER).0;
+}
+
+// New lines from now on, tagged with the name of the service that printed them
+pub fn follow() -> broadcast::Receiver<(Arc<str>, LogEntry)> {
+    LOG_EVENTS.subscribe()
+}
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub at: SystemTime,
+    pub stream: LogStream,
+    pub line: String,
+}
+
+impl LogEntry {
+    pub fn to_packet(&self) -> LogLine {
+        LogLine {
+            at_unix_ms: unix_ms(self.at),
+            stream: self.stream as u8,
+            line_len: self.line.len() as u32,
+            line: self.line.as_bytes().to_vec(),
+        }
+    }
+}
+
+// Ring buffer of the most recent output of a service, shared between its output
+// readers and whoever queries it. Survives restarts of the service
+//
+// Lines are also written through to `file` when the service logs to disk
+#[derive(Debug)]
+pub struct LogBuffer {
+    lines: VecDeque<LogEntry>,
+    capacity: usize,
+    file: Option<RotatingFile>,
+}
+
+pub type SharedLogs = Arc<Mutex<LogBuffer>>;
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> LogBuffer {
+        LogBuffer {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+            file: None,
+        }
+    }
+
+    // Keeps the file of a previous spawn open, restarts append to it
+    pub fn open_file(&mut self, name: &str, config: &LogFileConfig) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(RotatingFile::open(name, config)?);
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, entry: LogEntry) {
+        if let Some(file) = self.file.as_mut() {
+            let line = f!(
+                "{} {} | {}",
+                format_utc(entry.at),
+                stream_tag(entry.stream),
+                entry.line
+            );
+            if let Err(e) = file.write_line(&line) {
+                // Full disk or the directory went away, don't retry on every line
+                log::error!("Stopped writing log file: {}", e);
+                self.file = None;
+            }
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(entry);
+    }
+
+    // The last `tail` lines (all of them for 0) logged at or after `since`, oldest first
+    pub fn query(
+        &self,
+        tail: usize,
+        since: Option<SystemTime>,
+        stream: Option<LogStream>,
+    ) -> Vec<&LogEntry> {
+        let mut lines: Vec<&LogEntry> = self
+            .lines
+            .iter()
+            .rev()
+            .filter(|entry| since.is_none_or(|since| entry.at >= since))
+            .filter(|entry| stream.is_none_or(|stream| entry.stream == stream))
+            .take(if tail == 0 { usize::MAX } else { tail })
+            .collect();
+        lines.reverse();
+        lines
+    }
+}
+
+// What a follower asked for, services are matched by name
+#[derive(Debug)]
+pub struct LogFilter {
+    // None follows every service
+    pub services: Option<HashSet<String>>,
+    pub stream: Option<LogStream>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, service: &str, entry: &LogEntry) -> bool {
+        self.services
+            .as_ref()
+            .is_none_or(|services| services.contains(service))
+            && self.stream.is_none_or(|stream| entry.stream == stream)
+    }
+}
+
+pub fn stream_tag(stream: LogStream) -> &'static str {
+    match stream {
+        LogStream::Stdout => "out",
+        LogStream::Stderr => "err",
+    }
+}
+
+pub fn unix_ms(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|at| at.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Reads one of the child's pipes until it closes, logging every line prefixed with the
+// service name and stream, keeping it in `logs` and handing it to `on_line`. Lines are
+// read whole so readiness regexes can match on them, up to MAX_LINE_LEN, and a \r ends
+// one as well
+//
+// Draining both pipes matters, a child blocks forever once a pipe nobody reads fills up
+pub fn drain_output(
+    name: &str,
+    stream: LogStream,
+    pipe: impl Read + Send + 'static,
+    logs: SharedLogs,
+    mut on_line: impl FnMut(&str) + Send + 'static,
+) -> JoinHandle<()> {
+    let name: Arc<str> = Arc::from(name);
+    let tag = stream_tag(stream);
+    thread::spawn(move || {
+        let mut pipe = BufReader::new(pipe);
+        let mut line = vec![];
+        let mut after_cr = false;
+
+        loop {
+            line.clear();
+            match read_line(&mut pipe, &mut line, &mut after_cr) {
+                Ok(0) => break,
+                Ok(_) => {
+                    let output = String::from_utf8_lossy(&line);
+                    let output = output.trim_end();
+                    info!("[{} {}] {}", name, tag, output);
+                    on_line(output);
+                    let entry = LogEntry {
+                        at: SystemTime::now(),
+                        stream,
+                        line: output.to_string(),
+                    };
+                    // Fails when nobody is following, which is fine
+                    let _ = LOG_EVENTS.send((name.clone(), entry.clone()));
+                    logs.lock().unwrap().push(entry);
+                }
+                Err(e) => {
+                    log::error!("Error reading {} std{}: {}", name, tag, e);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+// Reads up to a \n or a \r, or MAX_LINE_LEN bytes, into `line`, without the line break.
+// Returns the bytes read, 0 once the pipe is closed
+//
+// The \n of a \r\n is skipped at the start of the next line, and a \r doesn't end a line
+// that is still empty, progress bars tend to start with one
+fn read_line(
+    pipe: &mut impl BufRead,
+    line: &mut Vec<u8>,
+    after_cr: &mut bool,
+) -> io::Result<usize> {
+    let mut read = 0;
+    loop {
+        let buf = pipe.fill_buf()?;
+        if buf.is_empty() {
+            // Nothing but skipped line breaks is no line
+            return Ok(if line.is_empty() { 0 } else { read });
+        }
+        if std::mem::take(after_cr) && buf[0] == b'\n' {
+            pipe.consume(1);
+            continue;
+        }
+
+        let chunk = &buf[..buf.len().min(MAX_LINE_LEN - line.len())];
+        let (len, line_break) = match chunk.iter().position(|&b| b == b'\n' || b == b'\r') {
+            Some(i) => {
+                line.extend_from_slice(&chunk[..i]);
+                (i + 1, Some(chunk[i]))
+            }
+            None => {
+                line.extend_from_slice(chunk);
+                (chunk.len(), None)
+            }
+        };
+        pipe.consume(len);
+        read += len;
+        *after_cr = line_break == Some(b'\r');
+        match line_break {
+            Some(b'\n') => return Ok(read),
+            Some(_) if !line.is_empty() => return Ok(read),
+            None if line.len() == MAX_LINE_LEN => return Ok(read),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(secs: u64, stream: LogStream, line: &str) -> LogEntry {
+        LogEntry {
+            at: UNIX_EPOCH + Duration::from_secs(secs),
+            stream,
+            line: line.to_string(),
+        }
+    }
+
+    #[test]
+    fn lines_end_at_carriage_returns_and_are_capped() {
+        let long = "x".repeat(MAX_LINE_LEN + 10);
+        let output = f!("a\r\nb\n\n\rprogress 1\rprogress 2\r\n{}\nc\n\r", long);
+        let mut pipe = io::Cursor::new(output.into_bytes());
+        let (mut line, mut after_cr) = (vec![], false);
+        let mut lines = vec![];
+        while read_line(&mut pipe, &mut line, &mut after_cr).unwrap() > 0 {
+            lines.push(String::from_utf8(std::mem::take(&mut line)).unwrap());
+        }
+
+        let (start, rest) = long.split_at(MAX_LINE_LEN);
+        assert_eq!(
+            lines,
+            ["a", "b", "", "progress 1", "progress 2", start, rest, "c"]
+        );
+    }
+
+    #[test]
+    fn oldest_lines_are_dropped_and_queries_filter() {
+        let mut logs = LogBuffer::new(3);
+        logs.push(entry(1, LogStream::Stdout, "a"));
+        logs.push(entry(2, LogStream::Stderr, "b"));
+        logs.push(entry(3, LogStream::Stdout, "c"));
+        logs.push(entry(4, LogStream::Stderr, "d"));
+
+        let lines = |logs: Vec<&LogEntry>| -> Vec<String> {
+            logs.iter().map(|entry| entry.line.clone()).collect()
+        };
+        assert_eq!(lines(logs.query(0, None, None)), ["b", "c", "d"]);
+        assert_eq!(lines(logs.query(2, None, None)), ["c", "d"]);
+        assert_eq!(
+            lines(logs.query(0, None, Some(LogStream::Stderr))),
+            ["b", "d"]
+        );
+
+        let since = UNIX_EPOCH + Duration::from_secs(3);
+        assert_eq!(lines(logs.query(0, Some(since), None)), ["c", "d"]);
+        assert_eq!(
+            lines(logs.query(1, Some(since), Some(LogStream::Stdout))),
+            ["c"]
+        );
+    }
+}
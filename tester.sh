@@ -4,8 +4,14 @@
 IP=127.0.0.1
 PORT=8080
 
+# Handshake: magic "LMSH", protocol version 5 (u16 LE), client name "tester" (u16 LE length)
+HANDSHAKE="\x4C\x4D\x53\x48\x05\x00\x06\x00\x74\x65\x73\x74\x65\x72"
+
 # Define the buffer to send
-BUFFER="\x01\x0C\x04\x54\x45\x53\x54\x01\x00\x04\x65\x63\x68\x6F"
+# version (0x10), id (AttachService), data_size (u32 LE), then the Service payload
+# with u16 LE length prefixes: name "TEST", type 1, path "", cmd "echo", args "", port 0,
+# restart policy never, max restarts 0, window 0, delay 0, no readiness probe, no env
+BUFFER="\x10\x01\x2A\x00\x00\x00\x04\x00\x54\x45\x53\x54\x01\x00\x00\x04\x00\x65\x63\x68\x6F\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"
 
 # Send the buffer to the server using netcat
-echo -ne $BUFFER | nc $IP $PORT
+echo -ne $HANDSHAKE$BUFFER | nc $IP $PORT